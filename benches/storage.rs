@@ -0,0 +1,51 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Compares the built-in storage engines under concurrent mixed load.
+// Run with `cargo bench`.
+
+use yocto::storage::{Engine, Storage};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const THREADS: usize = 4;
+const OPS: usize = 100_000;
+const KEYS: usize = 10_000;
+
+fn main() {
+    for (name, engine) in &[
+        ("hashed", Engine::Hashed),
+        ("ordered", Engine::Ordered),
+        ("sharded", Engine::Sharded(16)),
+        ("mapped", Engine::Mapped(std::env::temp_dir().join(format!("yocto-bench-{}", std::process::id()))))
+    ] {
+        engine.persisted().unwrap();
+        let storage: Arc<dyn Storage> = Arc::from(engine.create(name).unwrap());
+        let start = Instant::now();
+
+        let handles: Vec<_> = (0..THREADS).map(|t| {
+            let storage = Arc::clone(&storage);
+            thread::spawn(move || {
+                for i in 0..OPS {
+                    let key = format!("key{}", (i * 31 + t) % KEYS);
+                    if i % 4 == 0 {
+                        storage.insert(key, i.to_string()).unwrap();
+                    } else {
+                        storage.get(&key);
+                    }
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        let ops = (THREADS * OPS) as f64 / elapsed.as_secs_f64();
+        println!("{:8} {:>10.0} ops/s ({} threads, 25% writes)", name, ops, THREADS);
+    }
+}
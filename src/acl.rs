@@ -0,0 +1,208 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Users are defined in an ACL file, one per line, in a subset of the Redis ACL
+// syntax. Empty lines and lines starting with `#` are ignored:
+//
+//     user ops >secret +@all +@admin ~*
+//     user team-a >pa55 +@all -CLEAR ~team-a:*
+//     user reports >pw +GET +TEST %R~*
+//
+// `>password` adds a password, `+@all` allows all commands except those
+// changing the server as a whole, which `+@admin` allows. `+COMMAND` and
+// `-COMMAND` allow or deny a single one. `~pattern` allows reading and writing
+// keys matching the glob pattern, `%R~pattern` only reading and `%W~pattern`
+// only writing. Commands operating on all keys of a database require `*`.
+//
+// A user authenticates by prefixing its request with `USER name password`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, RwLock};
+use crate::{error, Result, KEY_COMMANDS, SEP, WRITE_COMMANDS};
+
+// commands without a key that read or write all entries of a database
+const ALL_KEY_COMMANDS: &[&str] = &["CLEAR", "RANGE", "PREFIX", "DUMP", "RESTORE"];
+
+// commands that write files or replace, move or lock out the data of all
+// databases, which `+@all` does not include
+const ADMIN_COMMANDS: &[&str] = &[
+    "SAVE", "BGSAVE", "BGREWRITEAOF", "BACKUP", "ACL", "READONLY", "PROMOTE", "REPLICAOF", "SETSLOT", "MIGRATE"
+];
+
+/// A user and what it may do.
+#[derive(Debug, Default)]
+pub struct User {
+    pub name: String,
+    passwords: Vec<String>,
+    all_commands: bool,
+    admin_commands: bool,
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+    read: Vec<String>,
+    write: Vec<String>
+}
+
+impl User {
+    fn parse(line: &str) -> std::result::Result<User, String> {
+        let mut tokens = line.split_whitespace();
+        let name = match (tokens.next(), tokens.next()) {
+            (Some("user"), Some(name)) => name,
+            _ => return Err("expected user and name".to_string())
+        };
+
+        let mut user = User { name: name.to_string(), ..User::default() };
+        for token in tokens {
+            if let Some(password) = token.strip_prefix('>') {
+                user.passwords.push(password.to_string());
+            } else if token == "+@all" {
+                user.all_commands = true;
+            } else if token == "+@admin" {
+                user.admin_commands = true;
+            } else if let Some(command) = token.strip_prefix('+') {
+                user.denied.remove(command);
+                user.allowed.insert(command.to_string());
+            } else if let Some(command) = token.strip_prefix('-') {
+                user.allowed.remove(command);
+                user.denied.insert(command.to_string());
+            } else if let Some(pattern) = token.strip_prefix('~') {
+                user.read.push(pattern.to_string());
+                user.write.push(pattern.to_string());
+            } else if let Some(pattern) = token.strip_prefix("%R~") {
+                user.read.push(pattern.to_string());
+            } else if let Some(pattern) = token.strip_prefix("%W~") {
+                user.write.push(pattern.to_string());
+            } else {
+                return Err(format!("unknown rule {}", token));
+            }
+        }
+
+        if user.passwords.is_empty() {
+            return Err(format!("no password for user {}", user.name));
+        }
+        Ok(user)
+    }
+
+    /// Fails unless the user may run `command`.
+    pub fn check_command(&self, command: &str) -> Result<()> {
+        let category = if ADMIN_COMMANDS.contains(&command) { self.admin_commands } else { self.all_commands };
+        let allowed = !self.denied.contains(command) && (category || self.allowed.contains(command));
+        if !allowed {
+            return Err(Box::new(error::StorageError(format!("User {} may not run {}", self.name, command))));
+        }
+        Ok(())
+    }
+
+    /// Fails unless the user may run the command in `string`, on the keys it
+    /// reads or writes.
+    pub fn check(&self, string: &str) -> Result<()> {
+        let split: Vec<&str> = string.split(SEP).collect();
+        self.check_command(split[0])?;
+
+        let patterns = if WRITE_COMMANDS.contains(&split[0]) { &self.write } else { &self.read };
+        let allowed = if KEY_COMMANDS.contains(&split[0]) && split.len() > 1 {
+            patterns.iter().any(|pattern| matches(pattern, split[1]))
+        } else if ALL_KEY_COMMANDS.contains(&split[0]) {
+            patterns.iter().any(|pattern| pattern == "*")
+        } else {
+            true
+        };
+
+        if !allowed {
+            return Err(Box::new(error::StorageError(format!("User {} may not access these keys", self.name))));
+        }
+        Ok(())
+    }
+}
+
+/// The users defined in an ACL file.
+pub struct Acl {
+    path: String,
+    users: RwLock<HashMap<String, Arc<User>>>
+}
+
+impl Acl {
+    /// Loads the users defined in the file at `path`.
+    pub fn open(path: &str) -> Result<Acl> {
+        Ok(Acl { path: path.to_string(), users: RwLock::new(read(path)?) })
+    }
+
+    /// Reads the file again and returns the number of users. If it is invalid,
+    /// the users defined before are kept.
+    pub fn reload(&self) -> Result<usize> {
+        let users = read(&self.path)?;
+        let n = users.len();
+        *self.users.write().unwrap() = users;
+        info!("Loaded {} users from {}", n, self.path);
+        Ok(n)
+    }
+
+    /// Returns the names of all users.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.users.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the user `name` if `password` is one of its passwords.
+    pub fn login(&self, name: &[u8], password: &[u8]) -> Option<Arc<User>> {
+        let users = self.users.read().unwrap();
+        let user = users.get(std::str::from_utf8(name).ok()?)?;
+        let valid = user.passwords.iter().fold(false, |valid, p| valid | equal(p.as_bytes(), password));
+        Some(Arc::clone(user)).filter(|_| valid)
+    }
+}
+
+fn read(path: &str) -> Result<HashMap<String, Arc<User>>> {
+    let mut users = HashMap::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let user = User::parse(line).map_err(|e| {
+            error::StorageError(format!("{} line {}: {}", path, i + 1, e))
+        })?;
+        users.insert(user.name.clone(), Arc::new(user));
+    }
+    Ok(users)
+}
+
+/// Compares all bytes, so the time taken does not tell how many match.
+pub fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Returns whether `key` matches the glob `pattern`, where `*` matches any
+/// sequence of characters and `?` a single one.
+fn matches(pattern: &str, key: &str) -> bool {
+    let (pattern, key): (Vec<char>, Vec<char>) = (pattern.chars().collect(), key.chars().collect());
+    let (mut p, mut k) = (0, 0);
+    // position after the last star and the key position it was tried at
+    let mut backtrack = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, k));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            },
+            _ => match backtrack {
+                Some((star, at)) => {
+                    backtrack = Some((star, at + 1));
+                    p = star;
+                    k = at + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
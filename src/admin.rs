@@ -0,0 +1,218 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::sync::Arc;
+use crate::server::Server;
+use crate::slots::{self, Slots};
+use crate::{error, Response, Result, SEP};
+
+/// A command operating on the server as a whole rather than on a single database.
+pub type AdminCommand = Box<dyn Fn(Arc<Server>) -> Response>;
+
+/// Parses `string` into an admin command, or returns `None` if it names a
+/// database command.
+pub fn parse_command(string: &str) -> Result<Option<AdminCommand>> {
+    let split: Vec<String> = string.split(SEP).map(|s| s.to_string()).collect();
+
+    let command: AdminCommand = match split[0].as_ref() {
+
+        // Writes a snapshot and returns its checksum once it is on disk.
+        "SAVE" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                let checksum = server.save()?;
+                Ok(Some(format!("{:08x}", checksum)))
+            })
+        },
+
+        // Starts writing a snapshot in the background and returns immediately.
+        "BGSAVE" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                server.bgsave()?;
+                Ok(None)
+            })
+        },
+
+        // Starts compacting the append-only log in the background.
+        "BGREWRITEAOF" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                server.bgrewrite()?;
+                Ok(None)
+            })
+        },
+
+        // Starts writing a consistent backup of all databases to the given path in
+        // the background. Without a path, returns the progress of the running
+        // backup, or the checksum of the last one once it is on disk.
+        "BACKUP" => {
+            match split.len() {
+                1 => Box::new(|server| Ok(Some(server.backup_status()?))),
+                2 => Box::new(move |server| {
+                    server.backup(&split[1])?;
+                    Ok(None)
+                }),
+                _ => return Err(Box::new(error::ParseError))
+            }
+        },
+
+        // Reloads the ACL file and returns the number of users with LOAD, or
+        // returns the names of all users with LIST.
+        "ACL" => {
+            if split.len() != 2 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(move |server| {
+                let acl = server.acl.as_ref().ok_or_else(|| {
+                    Box::new(error::StorageError("No ACL file is configured".to_string())) as Box<dyn std::error::Error>
+                })?;
+                match split[1].as_ref() {
+                    "LOAD" => Ok(Some(acl.reload()?.to_string())),
+                    "LIST" => Ok(Some(acl.names().join(&SEP.to_string())).filter(|names| !names.is_empty())),
+                    _ => Err(Box::new(error::ParseError))
+                }
+            })
+        },
+
+        // Rejects writes from clients with ON and accepts them again with OFF.
+        "READONLY" => {
+            if split.len() != 2 {
+                return Err(Box::new(error::ParseError));
+            }
+            let read_only = match split[1].as_ref() {
+                "ON" => true,
+                "OFF" => false,
+                _ => return Err(Box::new(error::ParseError))
+            };
+            Box::new(move |server| {
+                server.set_read_only(read_only);
+                Ok(None)
+            })
+        },
+
+        // Returns the bytes used by all databases and the configured limit.
+        "MEMORY" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                let memory = server.databases.memory().ok_or_else(|| {
+                    Box::new(error::StorageError("No memory limit configured".to_string())) as Box<dyn std::error::Error>
+                })?;
+                Ok(Some(format!("{}{}{}", memory.used(), SEP, memory.limit())))
+            })
+        },
+
+        // Returns `leader`, the replication id and offset, or `follower` followed
+        // by the address of the leader, the replication id and offset.
+        "ROLE" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                let (id, offset) = server.replication.position();
+                let role = match server.replication.leader() {
+                    Some(leader) => format!("follower{}{}", SEP, leader),
+                    None => "leader".to_string()
+                };
+                Ok(Some(format!("{}{}{}{}{}", role, SEP, id, SEP, offset)))
+            })
+        },
+
+        // Stops following the leader and accepts writes.
+        "PROMOTE" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                server.replication.promote()?;
+                Ok(None)
+            })
+        },
+
+        // Follows the leader at the given address from now on.
+        "REPLICAOF" => {
+            if split.len() != 2 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(move |server| {
+                // following a leader replaces the data set
+                server.writable()?;
+                server.replication.follow(split[1].clone(), Arc::clone(&server.databases));
+                Ok(None)
+            })
+        },
+
+        // Returns the slot of the given key.
+        "KEYSLOT" => {
+            if split.len() != 2 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(move |_| Ok(Some(slots::slot(&split[1]).to_string())))
+        },
+
+        // Returns the ranges of assigned slots and the slots being migrated.
+        "SLOTS" => {
+            if split.len() != 1 {
+                return Err(Box::new(error::ParseError));
+            }
+            Box::new(|server| {
+                let fields = sharded(&server)?.describe();
+                Ok(Some(fields.join(&SEP.to_string())).filter(|fields| !fields.is_empty()))
+            })
+        },
+
+        // Assigns a slot to a node, or marks it as migrating to or importing
+        // from a node.
+        "SETSLOT" => {
+            if split.len() != 4 {
+                return Err(Box::new(error::ParseError));
+            }
+            let slot: u16 = split[1].parse().map_err(|_| error::ParseError)?;
+            Box::new(move |server| {
+                server.writable()?;
+                let slots = sharded(&server)?;
+                match split[2].as_ref() {
+                    "NODE" => slots.assign(slot, &split[3])?,
+                    "MIGRATING" => slots.migrating(slot, &split[3])?,
+                    "IMPORTING" => slots.importing(slot, &split[3])?,
+                    _ => return Err(Box::new(error::ParseError))
+                }
+                Ok(None)
+            })
+        },
+
+        // Moves the entries of a migrating slot to its target and returns their
+        // number.
+        "MIGRATE" => {
+            if split.len() != 2 {
+                return Err(Box::new(error::ParseError));
+            }
+            let slot: u16 = split[1].parse().map_err(|_| error::ParseError)?;
+            Box::new(move |server| {
+                server.writable()?;
+                let moved = sharded(&server)?.migrate(slot, &server.databases)?;
+                Ok(Some(moved.to_string()))
+            })
+        },
+
+        _ => return Ok(None)
+    };
+
+    Ok(Some(command))
+}
+
+fn sharded(server: &Server) -> Result<&Slots> {
+    server.slots.as_ref().ok_or_else(|| {
+        Box::new(error::StorageError("Keys are not sharded".to_string())) as Box<dyn std::error::Error>
+    })
+}
\ No newline at end of file
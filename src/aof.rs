@@ -0,0 +1,349 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use crate::args::{Fsync, Recovery};
+use crate::codec::{frame, frame_after, invalid, write_str, FrameError, Reader};
+use crate::db::Databases;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+const CLEAR: u8 = 3;
+
+/// A mutation of the database named `db`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Insert { db: String, key: String, value: String },
+    Remove { db: String, key: String },
+    Clear { db: String }
+}
+
+impl Record {
+    pub fn db(&self) -> &str {
+        match self {
+            Record::Insert { db, .. } | Record::Remove { db, .. } | Record::Clear { db } => db
+        }
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        match self {
+            Record::Insert { db, key, value } => {
+                payload.push(INSERT);
+                write_str(&mut payload, db)?;
+                write_str(&mut payload, key)?;
+                write_str(&mut payload, value)?;
+            },
+            Record::Remove { db, key } => {
+                payload.push(REMOVE);
+                write_str(&mut payload, db)?;
+                write_str(&mut payload, key)?;
+            },
+            Record::Clear { db } => {
+                payload.push(CLEAR);
+                write_str(&mut payload, db)?;
+            }
+        }
+
+        Ok(frame(&payload))
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<Record> {
+        let mut reader = Reader::new(payload, 0);
+        let record = match reader.u8()? {
+            INSERT => Record::Insert { db: reader.string()?, key: reader.string()?, value: reader.string()? },
+            REMOVE => Record::Remove { db: reader.string()?, key: reader.string()? },
+            CLEAR => Record::Clear { db: reader.string()? },
+            _ => return Err(invalid("Unknown record type"))
+        };
+
+        if reader.remaining() > 0 {
+            return Err(invalid("Trailing bytes in record"));
+        }
+        Ok(record)
+    }
+}
+
+/// The file records are currently appended to.
+struct Log {
+    file: File,
+    size: u64,
+    // records appended while a rewrite is running, added to the rewritten log
+    // before it replaces the current one
+    rewrite_buffer: Option<Vec<u8>>
+}
+
+/// An append-only log of all mutations, replayed at startup.
+pub struct Aof {
+    path: String,
+    log: Mutex<Log>,
+    // handle to the same file, synced without blocking appends
+    sync_file: Mutex<File>,
+    fsync: Fsync,
+    // size right after the last rewrite, or at startup
+    base_size: AtomicU64,
+    rewrite_ratio: f64,
+    rewrite_min_size: u64
+}
+
+impl Aof {
+    /// Opens the log at `path` for appending, creating it if necessary.
+    pub fn open(path: &str, fsync: Fsync, rewrite_ratio: f64, rewrite_min_size: u64) -> io::Result<Arc<Aof>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let sync_file = file.try_clone()?;
+
+        let aof = Arc::new(Aof {
+            path: path.to_string(),
+            log: Mutex::new(Log { file, size, rewrite_buffer: None }),
+            sync_file: Mutex::new(sync_file),
+            fsync,
+            base_size: AtomicU64::new(size),
+            rewrite_ratio,
+            rewrite_min_size
+        });
+
+        if fsync == Fsync::EverySec {
+            let weak = Arc::downgrade(&aof);
+            thread::spawn(move || sync_every_second(weak));
+        }
+
+        Ok(aof)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Appends `record` to the log and runs `apply` while holding the log, so the
+    /// order of records matches the order in which they were applied. Returns the
+    /// result of `apply`, which is not run if the record cannot be written. If
+    /// `apply` fails, the record is removed from the log again.
+    pub fn append<F, R>(&self, record: &Record, apply: F) -> io::Result<R>
+        where
+            F: FnOnce() -> io::Result<R>
+    {
+        let frame = record.encode()?;
+        let mut log = self.log.lock().unwrap();
+        let size = log.size;
+        log.file.write_all(&frame)?;
+        log.size += frame.len() as u64;
+
+        if let Some(ref mut buffer) = log.rewrite_buffer {
+            buffer.extend_from_slice(&frame);
+        }
+
+        if self.fsync == Fsync::Always {
+            log.file.sync_data()?;
+        }
+
+        // a mutation that could not be applied is taken back out of the log
+        apply().or_else(|e| {
+            log.file.set_len(size)?;
+            log.size = size;
+            if let Some(ref mut buffer) = log.rewrite_buffer {
+                buffer.truncate(buffer.len() - frame.len());
+            }
+            Err(e)
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_file.lock().unwrap().sync_data()
+    }
+
+    /// Returns whether the log has outgrown the configured threshold since the
+    /// last rewrite.
+    pub fn needs_rewrite(&self) -> bool {
+        if self.rewrite_ratio <= 0.0 {
+            return false;
+        }
+
+        let log = self.log.lock().unwrap();
+        let base = self.base_size.load(Ordering::SeqCst);
+        log.rewrite_buffer.is_none()
+            && log.size > base
+            && log.size >= self.rewrite_min_size
+            && log.size as f64 >= base as f64 * self.rewrite_ratio
+    }
+
+    /// Starts buffering appended records for a rewrite. Fails if a rewrite is
+    /// already running.
+    pub fn begin_rewrite(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.rewrite_buffer.is_some() {
+            return Err(io::Error::other("A log rewrite is already in progress"));
+        }
+        log.rewrite_buffer = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Replaces the log with a minimal one inserting the current contents of
+    /// `databases`. Must be preceded by `begin_rewrite`. New records keep going
+    /// to the old log while the contents are written; they are then added to the
+    /// new log, which atomically replaces the old one. Returns the new size.
+    pub fn rewrite(&self, databases: &Databases) -> io::Result<u64> {
+        let result = self.write_rewrite(databases);
+        if result.is_err() {
+            self.log.lock().unwrap().rewrite_buffer = None;
+            let _ = fs::remove_file(self.rewrite_path());
+        }
+        result
+    }
+
+    fn write_rewrite(&self, databases: &Databases) -> io::Result<u64> {
+        let tmp = self.rewrite_path();
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+
+        // the snapshot loaded before the log may still hold keys removed since
+        for (name, db) in databases.all() {
+            writer.write_all(&Record::Clear { db: name.clone() }.encode()?)?;
+            for (key, value) in db.entries() {
+                writer.write_all(&Record::Insert { db: name.clone(), key, value }.encode()?)?;
+            }
+        }
+
+        let mut log = self.log.lock().unwrap();
+        if let Some(buffer) = log.rewrite_buffer.take() {
+            writer.write_all(&buffer)?;
+        }
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        *self.sync_file.lock().unwrap() = file.try_clone()?;
+        log.file = file;
+        log.size = size;
+        self.base_size.store(size, Ordering::SeqCst);
+
+        Ok(size)
+    }
+
+    fn rewrite_path(&self) -> String {
+        format!("{}.rewrite", self.path)
+    }
+
+    /// Reads all records of the log at `path` and passes them to `f` in order.
+    /// Returns the number of records read. The log is expected to have passed
+    /// `recover` before.
+    pub fn replay<F>(path: &str, mut f: F) -> io::Result<usize>
+        where
+            F: FnMut(Record) -> io::Result<()>
+    {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e)
+        };
+
+        let mut reader = Reader::new(&data, 0);
+        let mut count = 0;
+
+        while reader.remaining() > 0 {
+            let offset = reader.position();
+            let payload = reader.frame()
+                .map_err(|e| invalid(&format!("{:?} error in log record at offset {}", e, offset)))?;
+            f(Record::decode(payload)?)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Verifies every record of the log at `path` before it is opened. A record
+/// torn by a crash at the end of the log, with no intact record after it, is
+/// truncated. Corruption in the middle
+/// of the log is an error in strict mode; in degraded mode, the log is copied to
+/// `<path>.corrupt` for inspection and truncated before the corrupt record, so
+/// that everything after it is lost.
+pub fn recover(path: &str, recovery: Recovery) -> io::Result<()> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    let mut reader = Reader::new(&data, 0);
+    let mut count = 0;
+
+    while reader.remaining() > 0 {
+        let offset = reader.position();
+        let error = match reader.frame() {
+            Ok(payload) => match Record::decode(payload) {
+                Ok(_) => {
+                    count += 1;
+                    continue;
+                },
+                Err(e) => format!("undecodable record ({})", e)
+            },
+
+            // a length running past the end of the log is only a torn write if
+            // no intact record follows, otherwise the length is corrupt
+            Err(FrameError::Truncated) if frame_after(&data, offset, |payload| Record::decode(payload).is_ok()) => {
+                "length exceeds the end of the log, but intact records follow".to_string()
+            },
+
+            // the last record was only partially written
+            Err(FrameError::Truncated) => {
+                warn!("Log {} ends with a torn record at offset {}, truncating {} bytes",
+                    path, offset, data.len() - offset);
+                return truncate(path, offset as u64);
+            },
+
+            Err(FrameError::Checksum) if reader.remaining() == 0 => {
+                warn!("Log {} ends with a torn record at offset {} (checksum mismatch), truncating {} bytes",
+                    path, offset, data.len() - offset);
+                return truncate(path, offset as u64);
+            },
+
+            Err(FrameError::Checksum) => "checksum mismatch".to_string()
+        };
+
+        error!("Log {} is corrupt at offset {} (record {}): {}. {} of {} bytes are unreadable.",
+            path, offset, count + 1, error, data.len() - offset, data.len());
+
+        return match recovery {
+            Recovery::Strict => Err(invalid(&format!(
+                "Corrupt log {} at offset {}; start with --recovery degraded to discard the rest", path, offset))),
+
+            Recovery::Degraded => {
+                let copy = format!("{}.corrupt", path);
+                fs::copy(path, &copy)?;
+                error!("Starting degraded: kept {} records, copied the corrupt log to {}", count, copy);
+                truncate(path, offset as u64)
+            }
+        };
+    }
+
+    Ok(())
+}
+
+fn truncate(path: &str, len: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+fn sync_every_second(aof: Weak<Aof>) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        match aof.upgrade() {
+            Some(aof) => if let Err(e) = aof.sync() {
+                error!("Failed to sync append-only log: {}", e);
+            },
+            None => break
+        }
+    }
+}
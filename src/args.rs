@@ -3,20 +3,372 @@
 // Released under the MIT license.
 //
 
-use clap::{Arg, App};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::str::FromStr;
+use clap::{Arg, App, ArgMatches, SubCommand};
 use log::LogLevelFilter;
+use crate::slots::SLOTS;
+use crate::storage::Engine;
+
+/// When the append-only log is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Fsync {
+    /// After every mutation, before it is acknowledged.
+    Always,
+
+    /// Once per second, from a background thread.
+    #[default]
+    EverySec,
+
+    /// Whenever the operating system decides to.
+    Never
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fsync, String> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySec),
+            "never" => Ok(Fsync::Never),
+            _ => Err(format!("Unknown fsync policy: {}", s))
+        }
+    }
+}
+
+/// How startup recovery treats corruption in the middle of a snapshot or log.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Recovery {
+    /// Refuse to start.
+    #[default]
+    Strict,
+
+    /// Load everything before the corruption and start anyway.
+    Degraded
+}
+
+impl FromStr for Recovery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Recovery, String> {
+        match s {
+            "strict" => Ok(Recovery::Strict),
+            "degraded" => Ok(Recovery::Degraded),
+            _ => Err(format!("Unknown recovery mode: {}", s))
+        }
+    }
+}
+
+/// Which entries are evicted once the memory limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Eviction {
+    /// Reject writes that would exceed the limit.
+    #[default]
+    NoEviction,
+
+    /// Evict approximately the least recently used entry.
+    AllKeysLru,
+
+    /// Evict approximately the least frequently used entry.
+    AllKeysLfu,
+
+    /// Evict the entry closest to expiring. Entries never expire, so there is
+    /// nothing to pick from and this behaves like `NoEviction`.
+    VolatileTtl,
+
+    /// Evict a random entry.
+    Random
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Eviction, String> {
+        match s {
+            "noeviction" => Ok(Eviction::NoEviction),
+            "allkeys-lru" => Ok(Eviction::AllKeysLru),
+            "allkeys-lfu" => Ok(Eviction::AllKeysLfu),
+            "volatile-ttl" => Ok(Eviction::VolatileTtl),
+            "random" => Ok(Eviction::Random),
+            _ => Err(format!("Unknown eviction policy: {}", s))
+        }
+    }
+}
+
+/// The format entries are exported and imported in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// A JSON object with key and value per line.
+    #[default]
+    JsonLines,
+
+    /// A header followed by a key and value per record.
+    Csv
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("Unknown format: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv")
+        }
+    }
+}
+
+/// A range of IP addresses, such as `10.0.0.0/8` or a single address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix: u8
+}
+
+impl Cidr {
+    /// Returns whether `ip` is in the range. IPv4 addresses mapped to IPv6 are
+    /// treated as IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network.to_canonical(), ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid address range: {}", s);
+        let mut split = s.splitn(2, '/');
+        let network: IpAddr = split.next().unwrap_or_default().parse().map_err(|_| invalid())?;
+        let network = network.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match split.next() {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits).ok_or_else(invalid)?,
+            None => bits
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// Parses a comma-separated list of address ranges.
+pub fn parse_cidrs(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',').map(|cidr| cidr.trim().parse()).collect()
+}
 
 pub struct Config {
     pub threads: usize,
     pub iface: String,
     pub log_level: LogLevelFilter,
+    // number of versions retained per key, 0 disables history
+    pub history: usize,
+    // storage engine every database is created with
+    pub engine: Engine,
+    // file snapshots are loaded from at startup and saved to
+    pub snapshot: Option<String>,
+    // seconds between periodic snapshots, none disables them
+    pub save_interval: Option<u64>,
+    // directory BACKUP writes to, which disables it if none
+    pub backup_dir: Option<String>,
+    // append-only log of mutations, replayed at startup
+    pub aof: Option<String>,
+    pub fsync: Fsync,
+    // rewrite the log once it has grown to this multiple of its size after the
+    // last rewrite and is at least aof_rewrite_min_size bytes, 0 disables
+    pub aof_rewrite_ratio: f64,
+    pub aof_rewrite_min_size: u64,
+    pub recovery: Recovery,
+    // bytes all entries may occupy, none for no limit
+    pub max_memory: Option<usize>,
+    pub eviction: Eviction,
+    // address of the leader to replicate, if this is a follower
+    pub replica_of: Option<String>,
+    // bytes of recent records kept for followers to continue from
+    pub repl_backlog_size: usize,
+    // addresses of all cluster members including this one, empty outside of
+    // cluster mode
+    pub cluster: Vec<String>,
+    pub raft_dir: Option<String>,
+    // slot ranges and the nodes serving them, empty if keys are not sharded
+    pub slots: Vec<(u16, u16, String)>,
+    // password every request must be prefixed with, also sent to other instances
+    pub requirepass: Option<String>,
+    // users and their permissions, reloaded by ACL LOAD
+    pub acl_file: Option<String>,
+    // reject writes from clients until READONLY OFF
+    pub read_only: bool,
+    // requests per second allowed across all clients, from each client address
+    // and from each authenticated user, none for no limit
+    pub rate_limit: Option<u32>,
+    pub client_rate_limit: Option<u32>,
+    pub user_rate_limit: Option<u32>,
+    // connections are refused from addresses in deny, and from those not in
+    // allow unless it is empty
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    // file every write and admin command is appended to, and whether values
+    // are included
+    pub audit_log: Option<String>,
+    pub audit_values: bool,
     // used for testing
     pub exit_after: Option<usize>
 }
 
-pub fn get() -> Config {
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            threads: 4,
+            iface: "127.0.0.1:7001".to_string(),
+            log_level: LogLevelFilter::Info,
+            history: 0,
+            engine: Engine::default(),
+            snapshot: None,
+            save_interval: None,
+            backup_dir: None,
+            aof: None,
+            fsync: Fsync::default(),
+            aof_rewrite_ratio: 2.0,
+            aof_rewrite_min_size: 1 << 20,
+            recovery: Recovery::default(),
+            max_memory: None,
+            eviction: Eviction::default(),
+            replica_of: None,
+            repl_backlog_size: 1 << 20,
+            cluster: Vec::new(),
+            raft_dir: None,
+            slots: Vec::new(),
+            requirepass: None,
+            acl_file: None,
+            read_only: false,
+            rate_limit: None,
+            client_rate_limit: None,
+            user_rate_limit: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            audit_log: None,
+            audit_values: false,
+            exit_after: None
+        }
+    }
+}
+
+/// The entries `yocto export` and `yocto import` transfer.
+pub struct Transfer {
+    // address of the server
+    pub iface: String,
+    pub db: String,
+    pub format: Format,
+    // file to write to or read from, standard output or input if none
+    pub file: Option<String>,
+    pub password: Option<String>
+}
+
+/// What the binary was asked to do.
+pub enum Action {
+    Serve(Box<Config>),
+    Export(Transfer),
+    Import(Transfer)
+}
+
+fn transfer_command<'a, 'b>(name: &'a str, about: &'a str, file: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+
+        .arg(Arg::with_name("iface")
+            .short("i")
+            .long("iface")
+            .takes_value(true)
+            .help("IP address and port of the server, default 127.0.0.1:7001"))
+
+        .arg(Arg::with_name("db")
+            .short("d")
+            .long("db")
+            .takes_value(true)
+            .help("Database, default default"))
+
+        .arg(Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["jsonl", "csv"])
+            .help("Format of the entries, default jsonl"))
+
+        .arg(Arg::with_name("password")
+            .short("a")
+            .long("password")
+            .takes_value(true)
+            .help("Password to authenticate with"))
+
+        .arg(Arg::with_name("file")
+            .help(file))
+}
+
+fn transfer(matches: &ArgMatches) -> Result<Transfer, String> {
+    Ok(Transfer {
+        iface: matches.value_of("iface").unwrap_or("127.0.0.1:7001").to_string(),
+        db: matches.value_of("db").unwrap_or("default").to_string(),
+        format: matches.value_of("format").unwrap_or("jsonl").parse()?,
+        file: matches.value_of("file").map(|s| s.to_string()),
+        password: matches.value_of("password").map(|s| s.to_string())
+    })
+}
+
+/// Parses the command line of the binary, see `parse`.
+pub fn get() -> Result<Action, String> {
+    parse(env::args_os(), env::vars_os())
+}
+
+/// Parses the command line `args`, including the binary name. Options not
+/// given on the command line are read from the variable in `vars` named after
+/// the option, e.g. `YOCTO_MAX_MEMORY`, then from the config file, then fall
+/// back to their defaults. Prints usage and exits on malformed arguments.
+pub fn parse<I, T, E, K, V>(args: I, vars: E) -> Result<Action, String>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+        E: IntoIterator<Item = (K, V)>,
+        K: Into<OsString>,
+        V: Into<OsString>
+{
     let matches = App::new("yocto: minimalistic in-memory key value store")
 
+        .subcommand(transfer_command("export", "Writes the entries of a database to a file",
+                                     "File to write to, default standard output"))
+
+        .subcommand(transfer_command("import", "Inserts the entries in a file into a database",
+                                     "File to read from, default standard input"))
+
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .help("TOML file setting any of the options below, overridden by the command line and environment"))
+
         .arg(Arg::with_name("threads")
             .short("t")
             .long("threads")
@@ -29,21 +381,382 @@ pub fn get() -> Config {
             .takes_value(true)
             .help("IP address and port, default 127.0.0.1:7001"))
 
+        .arg(Arg::with_name("history")
+            .long("history")
+            .takes_value(true)
+            .help("Number of versions retained per key, default 0 (disabled)"))
+
+        .arg(Arg::with_name("engine")
+            .short("e")
+            .long("engine")
+            .takes_value(true)
+            .possible_values(&["hashed", "ordered", "sharded", "mapped"])
+            .help("Storage engine, default hashed. Only ordered supports RANGE and PREFIX"))
+
+        .arg(Arg::with_name("mapped-dir")
+            .long("mapped-dir")
+            .takes_value(true)
+            .help("Directory the mapped engine keeps its files in, default data"))
+
+        .arg(Arg::with_name("snapshot")
+            .short("s")
+            .long("snapshot")
+            .takes_value(true)
+            .help("Snapshot file, loaded at startup and written by SAVE and BGSAVE"))
+
+        .arg(Arg::with_name("save-interval")
+            .long("save-interval")
+            .takes_value(true)
+            .help("Seconds between periodic snapshots"))
+
+        .arg(Arg::with_name("backup-dir")
+            .long("backup-dir")
+            .takes_value(true)
+            .help("Directory BACKUP writes to, which is disabled without one"))
+
+        .arg(Arg::with_name("aof")
+            .long("aof")
+            .takes_value(true)
+            .help("Append-only log file, replayed at startup"))
+
+        .arg(Arg::with_name("fsync")
+            .long("fsync")
+            .takes_value(true)
+            .possible_values(&["always", "everysec", "never"])
+            .help("When to sync the append-only log to disk, default everysec"))
+
+        .arg(Arg::with_name("aof-rewrite-ratio")
+            .long("aof-rewrite-ratio")
+            .takes_value(true)
+            .help("Rewrite the log once it has grown by this factor since the last rewrite, default 2, 0 disables"))
+
+        .arg(Arg::with_name("aof-rewrite-min-size")
+            .long("aof-rewrite-min-size")
+            .takes_value(true)
+            .help("Minimum log size in bytes for automatic rewrites, default 1048576"))
+
+        .arg(Arg::with_name("recovery")
+            .long("recovery")
+            .takes_value(true)
+            .possible_values(&["strict", "degraded"])
+            .help("Whether to refuse to start on a corrupt snapshot or log, default strict"))
+
+        .arg(Arg::with_name("max-memory")
+            .long("max-memory")
+            .takes_value(true)
+            .help("Maximum number of bytes entries, history, queued messages and locks may occupy"))
+
+        .arg(Arg::with_name("eviction")
+            .long("eviction")
+            .takes_value(true)
+            .possible_values(&["noeviction", "allkeys-lru", "allkeys-lfu", "volatile-ttl", "random"])
+            .help("What to do once the memory limit is reached, default noeviction"))
+
+        .arg(Arg::with_name("replica-of")
+            .long("replica-of")
+            .takes_value(true)
+            .help("Address of a leader to replicate, serving only reads"))
+
+        .arg(Arg::with_name("repl-backlog-size")
+            .long("repl-backlog-size")
+            .takes_value(true)
+            .help("Bytes of recent writes kept for reconnecting followers, default 1048576"))
+
+        .arg(Arg::with_name("cluster")
+            .long("cluster")
+            .takes_value(true)
+            .help("Comma-separated addresses of all cluster members, including the bound one, to run in cluster mode"))
+
+        .arg(Arg::with_name("raft-dir")
+            .long("raft-dir")
+            .takes_value(true)
+            .help("Directory to keep the Raft term, vote and log in"))
+
+        .arg(Arg::with_name("slots")
+            .long("slots")
+            .takes_value(true)
+            .help("Shards keys across nodes, e.g. 0-8191=host1:port,8192-16383=host2:port"))
+
+        .arg(Arg::with_name("requirepass")
+            .long("requirepass")
+            .takes_value(true)
+            .help("Password clients must authenticate with using AUTH"))
+
+        .arg(Arg::with_name("acl-file")
+            .long("acl-file")
+            .takes_value(true)
+            .help("File defining users, which authenticate using USER, and their permissions"))
+
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .help("Reject writes until READONLY OFF"))
+
+        .arg(Arg::with_name("no-read-only")
+            .long("no-read-only")
+            .conflicts_with("read-only")
+            .help("Accept writes, even if the config file or environment sets read-only"))
+
+        .arg(Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .takes_value(true)
+            .help("Requests per second allowed across all clients"))
+
+        .arg(Arg::with_name("client-rate-limit")
+            .long("client-rate-limit")
+            .takes_value(true)
+            .help("Requests per second allowed from each client address"))
+
+        .arg(Arg::with_name("user-rate-limit")
+            .long("user-rate-limit")
+            .takes_value(true)
+            .help("Requests per second allowed from each user of the ACL file"))
+
+        .arg(Arg::with_name("allow")
+            .long("allow")
+            .takes_value(true)
+            .help("Comma-separated address ranges to accept connections from, e.g. 10.0.0.0/8,127.0.0.1"))
+
+        .arg(Arg::with_name("deny")
+            .long("deny")
+            .takes_value(true)
+            .help("Comma-separated address ranges to refuse connections from"))
+
+        .arg(Arg::with_name("audit-log")
+            .long("audit-log")
+            .takes_value(true)
+            .help("File to append a record of every write and admin command to"))
+
+        .arg(Arg::with_name("audit-values")
+            .long("audit-values")
+            .help("Include inserted values in the audit log"))
+
+        .arg(Arg::with_name("no-audit-values")
+            .long("no-audit-values")
+            .conflicts_with("audit-values")
+            .help("Leave inserted values out of the audit log, even if the config file or environment includes them"))
+
         .arg(Arg::with_name("verbose")
             .short("v")
             .long("verbose")
             .help("Show verbose logs"))
 
-        .get_matches();
+        .arg(Arg::with_name("no-verbose")
+            .long("no-verbose")
+            .conflicts_with("verbose")
+            .help("Hide verbose logs, even if the config file or environment shows them"))
 
-    Config {
-        threads: matches.value_of("threads").unwrap_or("4").parse().unwrap(),
-        iface: matches.value_of("iface").unwrap_or("127.0.0.1:7001").to_string(),
-        log_level: if matches.is_present("verbose") {
+        .get_matches_from(args);
+
+    match matches.subcommand() {
+        ("export", Some(matches)) => return Ok(Action::Export(transfer(matches)?)),
+        ("import", Some(matches)) => return Ok(Action::Import(transfer(matches)?)),
+        _ => ()
+    }
+
+    let vars = vars.into_iter().map(|(key, value)| (key.into(), value.into())).collect();
+    let options = Options::new(&matches, vars)?;
+    let default = Config::default();
+
+    let config = Config {
+        threads: options.parse("threads")?.unwrap_or(default.threads),
+        iface: options.value("iface")?.unwrap_or(default.iface),
+        log_level: if options.flag("verbose")? {
             LogLevelFilter::Debug
         } else {
-            LogLevelFilter::Info
+            default.log_level
+        },
+        history: options.parse("history")?.unwrap_or(default.history),
+        engine: match (options.value("engine")?.as_deref(), options.value("mapped-dir")?) {
+            (Some("mapped"), Some(dir)) => Engine::Mapped(dir.into()),
+            (_, Some(_)) => return Err("mapped-dir requires engine mapped".to_string()),
+            _ => options.parse("engine")?.unwrap_or(default.engine)
         },
+        snapshot: options.value("snapshot")?,
+        save_interval: options.parse("save-interval")?,
+        backup_dir: options.value("backup-dir")?,
+        aof: options.value("aof")?,
+        fsync: options.parse("fsync")?.unwrap_or(default.fsync),
+        aof_rewrite_ratio: options.parse("aof-rewrite-ratio")?.unwrap_or(default.aof_rewrite_ratio),
+        aof_rewrite_min_size: options.parse("aof-rewrite-min-size")?.unwrap_or(default.aof_rewrite_min_size),
+        recovery: options.parse("recovery")?.unwrap_or(default.recovery),
+        max_memory: options.parse("max-memory")?,
+        eviction: options.parse("eviction")?.unwrap_or(default.eviction),
+        replica_of: options.value("replica-of")?,
+        repl_backlog_size: options.parse("repl-backlog-size")?.unwrap_or(default.repl_backlog_size),
+        cluster: options.value("cluster")?.map(|s| s.split(',').map(|s| s.to_string()).collect())
+            .unwrap_or(default.cluster),
+        raft_dir: options.value("raft-dir")?,
+        slots: options.value("slots")?.map(|s| parse_slots(&s)).transpose()?.unwrap_or(default.slots),
+        requirepass: options.value("requirepass")?,
+        acl_file: options.value("acl-file")?,
+        read_only: options.flag("read-only")?,
+        rate_limit: options.parse("rate-limit")?,
+        client_rate_limit: options.parse("client-rate-limit")?,
+        user_rate_limit: options.parse("user-rate-limit")?,
+        allow: options.value("allow")?.map(|s| parse_cidrs(&s)).transpose()?.unwrap_or(default.allow),
+        deny: options.value("deny")?.map(|s| parse_cidrs(&s)).transpose()?.unwrap_or(default.deny),
+        audit_log: options.value("audit-log")?,
+        audit_values: options.flag("audit-values")?,
         exit_after: None
+    };
+
+    if config.threads == 0 {
+        return Err("threads must be at least 1".to_string());
+    }
+    if config.save_interval == Some(0) {
+        return Err("save-interval must be at least 1".to_string());
+    }
+    if config.save_interval.is_some() && config.snapshot.is_none() {
+        return Err("save-interval requires snapshot".to_string());
     }
+    if let Some((start, end, _)) = config.slots.iter().find(|(start, end, _)| start > end || *end >= SLOTS) {
+        return Err(format!("Invalid slot range {}-{}: slots range from 0 to {}", start, end, SLOTS - 1));
+    }
+    if !config.cluster.is_empty() && config.acl_file.is_some() && config.requirepass.is_none() {
+        return Err("cluster with acl-file requires requirepass".to_string());
+    }
+    if config.audit_values && config.audit_log.is_none() {
+        return Err("audit-values requires audit-log".to_string());
+    }
+    Ok(Action::Serve(Box::new(config)))
+}
+
+// options of the server, as named on the command line
+const OPTIONS: &[&str] = &[
+    "threads", "iface", "verbose", "history", "engine", "mapped-dir", "snapshot", "save-interval", "backup-dir", "aof", "fsync",
+    "aof-rewrite-ratio", "aof-rewrite-min-size", "recovery", "max-memory", "eviction", "replica-of",
+    "repl-backlog-size", "cluster", "raft-dir", "slots", "requirepass", "acl-file", "read-only", "rate-limit",
+    "client-rate-limit", "user-rate-limit", "allow", "deny", "audit-log", "audit-values"
+];
+
+/// Looks up options on the command line, in the environment and in the config
+/// file, in that order. In the file and the environment, option names use
+/// underscores instead of dashes, and the environment variables are prefixed
+/// with `YOCTO_`. Empty environment variables count as unset. Boolean options
+/// set in the environment or the file are turned off with `--no-<name>`.
+struct Options<'a> {
+    matches: &'a ArgMatches<'a>,
+    vars: HashMap<OsString, OsString>,
+    // path and contents of the config file, if any
+    file: Option<(String, toml::value::Table)>
+}
+
+impl<'a> Options<'a> {
+    fn new(matches: &'a ArgMatches<'a>, vars: HashMap<OsString, OsString>) -> Result<Options<'a>, String> {
+        let path = match matches.value_of("config") {
+            Some(path) => Some(path.to_string()),
+            None => var(&vars, "YOCTO_CONFIG")?
+        };
+
+        let file = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                let table: toml::value::Table = toml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?;
+
+                if let Some(key) = table.keys().find(|key| !OPTIONS.contains(&key.replace('_', "-").as_str())) {
+                    return Err(format!("{}: unknown option {}", path, key));
+                }
+                Some((path, table))
+            },
+            None => None
+        };
+
+        Ok(Options { matches, vars, file })
+    }
+
+    /// Returns the value of option `name` and where it was set.
+    fn lookup(&self, name: &str) -> Result<Option<(String, String)>, String> {
+        if let Some(value) = self.matches.value_of(name) {
+            return Ok(Some((value.to_string(), format!("--{}", name))));
+        }
+        if self.matches.is_present(name) {
+            return Ok(Some(("true".to_string(), format!("--{}", name))));
+        }
+        let negated = format!("no-{}", name);
+        if self.matches.is_present(&negated) {
+            return Ok(Some(("false".to_string(), format!("--{}", negated))));
+        }
+
+        let key = format!("YOCTO_{}", name.replace('-', "_").to_uppercase());
+        if let Some(value) = var(&self.vars, &key)? {
+            return Ok(Some((value, key)));
+        }
+
+        let (path, table) = match self.file {
+            Some((ref path, ref table)) => (path, table),
+            None => return Ok(None)
+        };
+        let key = name.replace('-', "_");
+        let origin = format!("{} in {}", key, path);
+
+        let value = match table.get(&key) {
+            Some(value) => value,
+            None => return Ok(None)
+        };
+        let value = match value {
+            toml::Value::Array(values) => {
+                let values: Result<Vec<String>, String> = values.iter().map(|value| scalar(value, &origin)).collect();
+                values?.join(",")
+            },
+            value => scalar(value, &origin)?
+        };
+        Ok(Some((value, origin)))
+    }
+
+    fn value(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.lookup(name)?.map(|(value, _)| value))
+    }
+
+    fn parse<T>(&self, name: &str) -> Result<Option<T>, String>
+        where
+            T: FromStr,
+            T::Err: fmt::Display
+    {
+        match self.lookup(name)? {
+            Some((value, origin)) => value.parse().map(Some).map_err(|e| format!("Invalid {}: {}", origin, e)),
+            None => Ok(None)
+        }
+    }
+
+    fn flag(&self, name: &str) -> Result<bool, String> {
+        Ok(self.parse(name)?.unwrap_or(false))
+    }
+}
+
+/// Returns the environment variable `key` of `vars`, unless it is empty.
+fn var(vars: &HashMap<OsString, OsString>, key: &str) -> Result<Option<String>, String> {
+    match vars.get(OsStr::new(key)).map(|value| value.to_str()) {
+        Some(Some("")) | None => Ok(None),
+        Some(Some(value)) => Ok(Some(value.to_string())),
+        Some(None) => Err(format!("Invalid {}: not valid unicode", key))
+    }
+}
+
+/// Returns a string, number or boolean of the config file as a string.
+fn scalar(value: &toml::Value, origin: &str) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("Invalid {}: expected a string, number or boolean", origin))
+    }
+}
+
+/// Parses comma-separated slot assignments, each being `start-end=node` or
+/// `slot=node`.
+pub fn parse_slots(s: &str) -> Result<Vec<(u16, u16, String)>, String> {
+    s.split(',').map(|assignment| {
+        let invalid = || format!("Invalid slot assignment: {}", assignment);
+        let mut split = assignment.splitn(2, '=');
+        let (range, node) = (split.next().unwrap_or_default(), split.next().ok_or_else(invalid)?);
+
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds.next().unwrap_or_default().parse().map_err(|_| invalid())?;
+        let end = match bounds.next() {
+            Some(end) => end.parse().map_err(|_| invalid())?,
+            None => start
+        };
+        Ok((start, end, node.to_string()))
+    }).collect()
 }
\ No newline at end of file
@@ -0,0 +1,69 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// The audit log records every command that modifies a database and every admin
+// command a client runs, one JSON object per line:
+//
+//     {"time":1546300800000,"client":"10.0.0.5:51234","user":"ops","db":"default",
+//      "command":"REMOVE","key":"k","ok":true}
+//
+// `user` is null unless the client authenticated as a user of the ACL file.
+// Keys are recorded for commands on a single key and arguments for admin
+// commands. Values are only recorded if configured, and failed commands have
+// an `error` instead of being `ok`. Writes replicated from a leader or applied
+// by the cluster are recorded by the instance the client sent them to.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use serde_json::{json, Value};
+use crate::{now, Response, KEY_COMMANDS, SEP};
+
+pub struct Audit {
+    path: String,
+    file: Mutex<File>,
+    // whether to record the values of INSERT
+    values: bool
+}
+
+impl Audit {
+    /// Opens the audit log at `path` for appending, creating it if needed.
+    pub fn open(path: &str, values: bool) -> io::Result<Audit> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Audit { path: path.to_string(), file: Mutex::new(file), values })
+    }
+
+    /// Records that `client`, authenticated as `user` if any, ran the command
+    /// in `string` on the database `db` with the given `response`.
+    pub fn record(&self, client: &str, user: Option<&str>, db: &str, string: &str, admin: bool, response: &Response) {
+        let split: Vec<&str> = string.split(SEP).collect();
+        let mut entry = json!({
+            "time": now(),
+            "client": client,
+            "user": user,
+            "db": db,
+            "command": split[0]
+        });
+
+        if admin {
+            entry["args"] = json!(split[1..]);
+        } else if KEY_COMMANDS.contains(&split[0]) && split.len() > 1 {
+            entry["key"] = json!(split[1]);
+            if self.values && split[0] == "INSERT" && split.len() > 2 {
+                entry["value"] = json!(split[2]);
+            }
+        }
+
+        match response {
+            Ok(_) => entry["ok"] = Value::Bool(true),
+            Err(e) => entry["error"] = json!(e.to_string())
+        }
+
+        let line = entry.to_string() + "\n";
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write audit log {}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// An online backup copies all databases at a single replication offset, then
+// writes the copy to a snapshot file on a separate thread while the server
+// keeps serving. The file can be loaded at startup like any snapshot.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::db::Databases;
+use crate::replication::Replication;
+use crate::{snapshot, SEP};
+
+/// A backup that is being written or has finished.
+pub struct Backup {
+    path: String,
+    offset: u64,
+    total: u64,
+    written: AtomicU64,
+    // the checksum of the file or the error, once finished
+    result: Mutex<Option<Result<u32, String>>>
+}
+
+impl Backup {
+    /// Copies all databases and starts writing them to `path`.
+    pub fn start(databases: &Databases, replication: &Replication, path: &str) -> Arc<Backup> {
+        let (copy, offset) = snapshot::copy(databases, replication);
+
+        let backup = Arc::new(Backup {
+            path: path.to_string(),
+            offset,
+            total: copy.iter().map(|(_, entries)| entries.len() as u64).sum(),
+            written: AtomicU64::new(0),
+            result: Mutex::new(None)
+        });
+        info!("Writing backup of {} entries at offset {} to {}", backup.total, offset, path);
+
+        let this = Arc::clone(&backup);
+        thread::spawn(move || {
+            let result = snapshot::write(&this.path, copy, |n| this.written.store(n, Ordering::Relaxed));
+            match result {
+                Ok(checksum) => info!("Wrote backup to {} (checksum {:08x})", this.path, checksum),
+                Err(ref e) => error!("Failed to write backup to {}: {}", this.path, e)
+            }
+            *this.result.lock().unwrap() = Some(result.map_err(|e| e.to_string()));
+        });
+
+        backup
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.result.lock().unwrap().is_none()
+    }
+
+    /// Returns `running`, the path, the offset, the entries written and their
+    /// total, or `done` followed by the path, the offset, the total and the
+    /// checksum, or `failed` followed by the path, the offset and the error.
+    pub fn status(&self) -> String {
+        let fields = match *self.result.lock().unwrap() {
+            None => vec![
+                "running".to_string(),
+                self.path.clone(),
+                self.offset.to_string(),
+                self.written.load(Ordering::Relaxed).to_string(),
+                self.total.to_string()
+            ],
+            Some(Ok(checksum)) => vec![
+                "done".to_string(),
+                self.path.clone(),
+                self.offset.to_string(),
+                self.total.to_string(),
+                format!("{:08x}", checksum)
+            ],
+            Some(Err(ref e)) => vec!["failed".to_string(), self.path.clone(), self.offset.to_string(), e.clone()]
+        };
+        fields.join(&SEP.to_string())
+    }
+}
@@ -0,0 +1,135 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Helpers for the length-prefixed little-endian encoding shared by the
+// snapshot, the append-only log and the replication stream. All consist of
+// frames, each holding the payload length, the CRC32 of the payload and the
+// payload itself.
+
+use std::io::{self, Read, Write};
+
+pub fn write_str<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u32).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Wraps `payload` into a frame.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a frame from `reader` and returns its payload after verifying the
+/// checksum.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    let mut header = Reader::new(&header, 0);
+    let len = header.u32()? as usize;
+    let checksum = header.u32()?;
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    if crc32fast::hash(&payload) != checksum {
+        return Err(invalid("Checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+/// Why a frame could not be read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    /// The data ends before the frame does.
+    Truncated,
+
+    /// The payload does not match its checksum.
+    Checksum
+}
+
+/// Returns whether a frame whose payload is accepted by `valid` starts anywhere
+/// in `data` after `offset`. A frame that cannot be read is only torn by a crash
+/// if it is the last one, so nothing intact may follow it.
+pub fn frame_after<F>(data: &[u8], offset: usize, valid: F) -> bool
+    where
+        F: Fn(&[u8]) -> bool
+{
+    (offset + 1..data.len()).any(|at| match Reader::new(data, at).frame() {
+        Ok(payload) => !payload.is_empty() && valid(payload),
+        Err(_) => false
+    })
+}
+
+/// Reads length-prefixed fields from a byte slice.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], pos: usize) -> Reader<'a> {
+        Reader { data, pos }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.remaining() < n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected end of data"));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads a frame and returns its payload after verifying the checksum.
+    pub fn frame(&mut self) -> Result<&'a [u8], FrameError> {
+        if self.remaining() < 8 {
+            return Err(FrameError::Truncated);
+        }
+
+        let len = self.u32().map_err(|_| FrameError::Truncated)? as usize;
+        let checksum = self.u32().map_err(|_| FrameError::Truncated)?;
+        let payload = self.take(len).map_err(|_| FrameError::Truncated)?;
+
+        if crc32fast::hash(payload) != checksum {
+            return Err(FrameError::Checksum);
+        }
+        Ok(payload)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("Invalid UTF-8 in string"))
+    }
+}
@@ -0,0 +1,298 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock, Weak};
+use crate::aof::{Aof, Record};
+use crate::storage::{Engine, Storage};
+use crate::history::{History, Version};
+use crate::locks::Locks;
+use crate::memory::{self, Memory};
+use crate::queues::Queues;
+use crate::replication::Replication;
+use crate::{now, Result};
+
+/// The key-value map together with the bookkeeping every command operates on.
+pub struct Database {
+    name: String,
+    map: Box<dyn Storage>,
+    history: History,
+    aof: Option<Arc<Aof>>,
+    memory: Option<Arc<Memory>>,
+    replication: Arc<Replication>,
+    // the databases this one belongs to, to evict entries from
+    databases: Weak<Databases>,
+    pub locks: Locks,
+    pub queues: Queues
+}
+
+impl Database {
+    pub fn new(name: &str, history: usize, map: Box<dyn Storage>, aof: Option<Arc<Aof>>,
+               memory: Option<Arc<Memory>>, replication: Arc<Replication>, databases: Weak<Databases>) -> Database {
+        let db = Database {
+            name: name.to_string(),
+            map,
+            history: History::new(history, memory.clone()),
+            aof,
+            replication,
+            databases,
+            locks: Locks::new(memory.clone()),
+            queues: Queues::new(memory.clone()),
+            memory
+        };
+
+        // entries persisted by the storage engine count against the limit
+        if db.memory.is_some() && !db.map.is_empty() {
+            for (key, value) in db.map.entries() {
+                db.account(&key, &value);
+            }
+        }
+        db
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some(ref memory) = self.memory {
+            memory.touch(&self.name, key);
+        }
+        self.map.get(key)
+    }
+
+    /// Inserts `value` at `key` and returns the old value, if existing. Fails if
+    /// the entry does not fit into the memory limit and nothing may be evicted.
+    pub fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        let size = memory::entry_size(&key, &value);
+        // the new version is kept in the history as well
+        let versioned = if self.history.enabled() { memory::version_size(&value) } else { 0 };
+        self.make_room(Some(&key), size + versioned)?;
+
+        let (k, v) = (key.clone(), value.clone());
+        let old = self.history.record(&k, Some(&v), now(), || {
+            self.log(Record::Insert { db: self.name.clone(), key: k.clone(), value: v.clone() },
+                || self.map.insert(key, value))
+        })?;
+
+        if let Some(ref memory) = self.memory {
+            memory.insert(&self.name, &k, size);
+        }
+        Ok(old)
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        let old = self.history.record(key, None, now(), || {
+            self.log(Record::Remove { db: self.name.clone(), key: key.to_string() },
+                || self.map.remove(key))
+        })?;
+
+        if let Some(ref memory) = self.memory {
+            memory.remove(&self.name, key);
+        }
+        Ok(old)
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        let at = now();
+        let keys = self.log(Record::Clear { db: self.name.clone() }, || self.map.clear())?;
+
+        if let Some(ref memory) = self.memory {
+            for key in &keys {
+                memory.remove(&self.name, key);
+            }
+        }
+
+        if self.history.enabled() {
+            for key in keys {
+                self.history.record(&key, None, at, || Ok(()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `payload` to `queue` and returns the new message id. Fails if the
+    /// message does not fit into the memory limit and nothing may be evicted.
+    pub fn enqueue(&self, queue: &str, payload: String) -> Result<u64> {
+        self.make_room(None, memory::message_size(&payload))?;
+        Ok(self.queues.enqueue(queue, payload))
+    }
+
+    /// Evicts entries until `size` bytes, for `key` if given, fit into the
+    /// memory limit. Evicted entries are removed like any other, so the removal
+    /// is logged and kept in their history.
+    fn make_room(&self, key: Option<&str>, size: usize) -> Result<()> {
+        let memory = match self.memory {
+            Some(ref memory) => memory,
+            None => return Ok(())
+        };
+
+        while let Some((db, victim)) = memory.victim(&self.name, key, size)? {
+            debug!("Evicting {} from database {}", victim, db);
+            match self.databases.upgrade().and_then(|databases| databases.find(&db)) {
+                Some(db) => { db.remove(&victim)?; },
+                None => memory.remove(&db, &victim)
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a mutation read back from the log, bypassing the history.
+    pub fn replay(&self, record: Record) -> io::Result<()> {
+        match record {
+            Record::Insert { key, value, .. } => {
+                self.account(&key, &value);
+                self.map.insert(key, value)?;
+            },
+            Record::Remove { key, .. } => {
+                self.unaccount(&key);
+                self.map.remove(&key)?;
+            },
+            Record::Clear { .. } => {
+                for key in self.map.clear()? {
+                    self.unaccount(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn account(&self, key: &str, value: &str) {
+        if let Some(ref memory) = self.memory {
+            memory.insert(&self.name, key, memory::entry_size(key, value));
+        }
+    }
+
+    fn unaccount(&self, key: &str) {
+        if let Some(ref memory) = self.memory {
+            memory.remove(&self.name, key);
+        }
+    }
+
+    /// Runs `apply`, first appending `record` to the log if one is configured,
+    /// and sends `record` to the followers unless `apply` fails.
+    fn log<F, A>(&self, record: Record, apply: A) -> io::Result<F>
+        where
+            A: FnOnce() -> io::Result<F>
+    {
+        match self.aof {
+            Some(ref aof) => aof.append(&record, || self.replication.publish(&record, apply)),
+            None => self.replication.publish(&record, apply)
+        }
+    }
+
+    pub fn range(&self, start: &str, end: &str, limit: usize) -> Option<Vec<(String, String)>> {
+        self.map.range(start, end, limit)
+    }
+
+    pub fn prefix(&self, prefix: &str, limit: usize) -> Option<Vec<(String, String)>> {
+        self.map.prefix(prefix, limit)
+    }
+
+    /// Returns a copy of all entries.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        self.map.entries()
+    }
+
+    /// Loads `entries` into the map, bypassing the history.
+    pub fn restore(&self, entries: Vec<(String, String)>) -> io::Result<()> {
+        for (key, value) in entries {
+            self.account(&key, &value);
+            self.map.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.history.enabled()
+    }
+
+    pub fn history(&self, key: &str) -> Vec<Version> {
+        self.history.get(key)
+    }
+
+    pub fn get_at(&self, key: &str, at: u64) -> Option<String> {
+        self.history.at(key, at)
+    }
+}
+
+/// Independent databases addressed by name, created on first use.
+pub struct Databases {
+    history: usize,
+    engine: Engine,
+    aof: Option<Arc<Aof>>,
+    memory: Option<Arc<Memory>>,
+    replication: Arc<Replication>,
+    this: Weak<Databases>,
+    databases: RwLock<HashMap<String, Arc<Database>>>,
+    // read in place of databases that do not exist
+    empty: Arc<Database>
+}
+
+impl Databases {
+    pub fn new(history: usize, engine: Engine, aof: Option<Arc<Aof>>, memory: Option<Arc<Memory>>,
+               replication: Arc<Replication>) -> Arc<Databases> {
+        Arc::new_cyclic(|this| Databases {
+            history,
+            this: this.clone(),
+            databases: RwLock::new(HashMap::new()),
+            empty: Arc::new(Database::new("", history, engine.transient(), None, None, Arc::clone(&replication),
+                this.clone())),
+            engine,
+            aof,
+            memory,
+            replication
+        })
+    }
+
+    /// Returns the database `name`, creating it if it does not exist. Fails if
+    /// the storage engine cannot create it.
+    pub fn get(&self, name: &str) -> io::Result<Arc<Database>> {
+        if let Some(db) = self.find(name) {
+            return Ok(db);
+        }
+
+        let mut databases = self.databases.write().unwrap();
+        if let Some(db) = databases.get(name) {
+            return Ok(Arc::clone(db));
+        }
+
+        let storage = self.engine.create(name)?;
+        let db = Arc::new(Database::new(name, self.history, storage, self.aof.clone(), self.memory.clone(),
+            Arc::clone(&self.replication), self.this.clone()));
+        databases.insert(name.to_string(), Arc::clone(&db));
+        Ok(db)
+    }
+
+    /// Returns the database `name` if it exists.
+    pub fn find(&self, name: &str) -> Option<Arc<Database>> {
+        self.databases.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns the database `name` to read from, which is empty and not created
+    /// if it does not exist.
+    pub fn read(&self, name: &str) -> Arc<Database> {
+        self.find(name).unwrap_or_else(|| Arc::clone(&self.empty))
+    }
+
+    /// Opens the databases whose entries persist in the storage engine from a
+    /// previous run.
+    pub fn reopen(&self) -> io::Result<usize> {
+        let names = self.engine.persisted()?;
+        for name in &names {
+            self.get(name)?;
+        }
+        Ok(names.len())
+    }
+
+    /// Returns all databases created so far, with their names.
+    pub fn all(&self) -> Vec<(String, Arc<Database>)> {
+        self.databases.read().unwrap().iter()
+            .map(|(name, db)| (name.clone(), Arc::clone(db)))
+            .collect()
+    }
+
+    /// Returns the memory accounting, if a limit is configured.
+    pub fn memory(&self) -> Option<&Memory> {
+        self.memory.as_deref()
+    }
+}
@@ -19,7 +19,7 @@ impl error::Error for ParseError {
         "Unable to parse command"
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
@@ -38,7 +38,94 @@ impl error::Error for StorageError {
         self.0.as_ref()
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadOnlyError;
+
+impl fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "READONLY Server is in read-only mode")
+    }
+}
+
+impl error::Error for ReadOnlyError {
+    fn description(&self) -> &str {
+        "Server is in read-only mode"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+/// Rejects a request of a client, user or the server as a whole that exceeds
+/// its limit of `rate` requests per second.
+#[derive(Debug, Clone)]
+pub struct RateLimitError {
+    pub scope: String,
+    pub rate: u32
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RATELIMIT {} exceeded {} requests per second", self.scope, self.rate)
+    }
+}
+
+impl error::Error for RateLimitError {
+    fn description(&self) -> &str {
+        "Rate limit exceeded"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutOfMemoryError;
+
+impl fmt::Display for OutOfMemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Memory limit reached")
+    }
+}
+
+impl error::Error for OutOfMemoryError {
+    fn description(&self) -> &str {
+        "Memory limit reached"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+/// Tells the client which node serves a key instead. `ask` redirects only the
+/// current request, for a slot that is being migrated.
+#[derive(Debug, Clone)]
+pub struct RedirectError {
+    pub ask: bool,
+    pub slot: u16,
+    pub node: String
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", if self.ask { "ASK" } else { "MOVED" }, self.slot, self.node)
+    }
+}
+
+impl error::Error for RedirectError {
+    fn description(&self) -> &str {
+        "Key is served by another node"
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
\ No newline at end of file
@@ -0,0 +1,116 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use chashmap::CHashMap;
+use crate::memory::{self, Memory};
+
+/// A single value a key held during the time span `[since, until)`. A version
+/// without `until` is the one currently stored.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub value: String,
+    pub since: u64,
+    pub until: Option<u64>
+}
+
+/// Keeps the last `retention` versions of every key, with timestamps.
+pub struct History {
+    retention: usize,
+    versions: CHashMap<String, VecDeque<Version>>,
+    // charged for the retained values
+    memory: Option<Arc<Memory>>
+}
+
+impl History {
+    pub fn new(retention: usize, memory: Option<Arc<Memory>>) -> History {
+        History {
+            retention,
+            versions: CHashMap::new(),
+            memory
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.retention > 0
+    }
+
+    /// Runs `write` while holding the lock on the versions of `key`, then closes
+    /// the current version at `at` and, if `value` is set, starts a new one.
+    /// Nothing is recorded if `write` fails.
+    pub fn record<F, R>(&self, key: &str, value: Option<&str>, at: u64, write: F) -> io::Result<R>
+        where
+            F: FnOnce() -> io::Result<R>
+    {
+        if !self.enabled() {
+            return write();
+        }
+
+        let mut result = None;
+        let retention = self.retention;
+
+        self.versions.alter(key.to_string(), |versions| {
+            let written = write();
+            let failed = written.is_err();
+            result = Some(written);
+            if failed {
+                return versions;
+            }
+            let mut versions = versions.unwrap_or_default();
+
+            if let Some(current) = versions.back_mut() {
+                if current.until.is_none() && current.since <= at {
+                    current.until = Some(at);
+                }
+            }
+
+            if let Some(value) = value {
+                self.charge(value);
+                versions.push_back(Version { value: value.to_string(), since: at, until: None });
+            }
+
+            while versions.len() > retention {
+                if let Some(version) = versions.pop_front() {
+                    self.release(&version.value);
+                }
+            }
+
+            if versions.is_empty() { None } else { Some(versions) }
+        });
+
+        result.unwrap()
+    }
+
+    fn charge(&self, value: &str) {
+        if let Some(ref memory) = self.memory {
+            memory.charge(memory::version_size(value));
+        }
+    }
+
+    fn release(&self, value: &str) {
+        if let Some(ref memory) = self.memory {
+            memory.release(memory::version_size(value));
+        }
+    }
+
+    /// Returns all retained versions of `key`, oldest first.
+    pub fn get(&self, key: &str) -> Vec<Version> {
+        match self.versions.get(key) {
+            Some(versions) => versions.iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Returns the value `key` held at timestamp `at`, if it is still retained.
+    pub fn at(&self, key: &str, at: u64) -> Option<String> {
+        self.versions.get(key).and_then(|versions| {
+            versions.iter()
+                .find(|v| v.since <= at && v.until.is_none_or(|until| at < until))
+                .map(|v| v.value.clone())
+        })
+    }
+}
@@ -9,20 +9,62 @@ extern crate log;
 pub mod args;
 pub mod logo;
 pub mod logger;
+pub mod storage;
+pub mod transfer;
 mod threadp;
 mod error;
+mod history;
+mod locks;
+mod queues;
+mod db;
+mod codec;
+mod snapshot;
+mod aof;
+mod server;
+mod admin;
+mod memory;
+mod replication;
+mod raft;
+mod slots;
+mod backup;
+mod acl;
+mod limits;
+mod audit;
 
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, TcpListener, TcpStream};
 use std::{process, result, str, io};
 use std::sync::Arc;
-use chashmap::{CHashMap};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use db::Database;
+use server::Server;
 
-type Result<T> = result::Result<T, Box<std::error::Error>>;
+type Result<T> = result::Result<T, Box<dyn std::error::Error>>;
 type Response = Result<Option<String>>;
-type Command = Box<Fn(Arc<CHashMap<String, String>>) -> Response>;
+type Command = Box<dyn Fn(Arc<Database>) -> Response>;
 
 const SEP: char = '\u{1f}';
+const DEFAULT_DB: &str = "default";
+
+// longest pause between reads of the data of a restore before it is abandoned
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// commands whose second field is the key they operate on
+const KEY_COMMANDS: &[&str] = &[
+    "GET", "INSERT", "REMOVE", "HISTORY", "LOCK", "UNLOCK", "REFRESH", "ENQUEUE", "DEQUEUE", "ACK", "NACK"
+];
+
+// commands that modify a database and are rejected by followers
+const WRITE_COMMANDS: &[&str] = &[
+    "INSERT", "REMOVE", "CLEAR", "LOCK", "UNLOCK", "REFRESH", "ENQUEUE", "DEQUEUE", "ACK", "NACK", "RESTORE"
+];
+
+/// Returns the current time in milliseconds since the unix epoch.
+fn now() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
 
 fn parse_command(string: String) -> Result<Command> {
     let split: Vec<String> = string.split(SEP).map(|s| s.to_string()).collect();
@@ -34,17 +76,22 @@ fn parse_command(string: String) -> Result<Command> {
         },
 
         // Locates the given key inside the database and returns an Ok with the
-        // corresponding value if existing or an None if not.
+        // corresponding value if existing or an None if not. With `AT timestamp`,
+        // returns the value the key held at that point in time instead.
         "GET" => {
-            if split.len() != 2 {
+            if split.len() == 4 && split[2] == "AT" {
+                let at: u64 = split[3].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    if !db.history_enabled() {
+                        return Err(Box::new(error::StorageError("History is disabled".to_string())));
+                    }
+                    Ok(db.get_at(&split[1], at))
+                }))
+            } else if split.len() != 2 {
                 Err(Box::new(error::ParseError))
             } else {
-                Ok(Box::new(move |map| {
-                    if let Some(rg) = map.get(&split[1]) {
-                        Ok(Some(rg.to_string()))
-                    } else {
-                        Ok(None)
-                    }
+                Ok(Box::new(move |db| {
+                    Ok(db.get(&split[1]))
                 }))
             }
         },
@@ -54,12 +101,8 @@ fn parse_command(string: String) -> Result<Command> {
             if split.len() != 3 {
                 Err(Box::new(error::ParseError))
             } else {
-                Ok(Box::new(move |map| {
-                    if let Some(old) = map.insert(split[1].clone(), split[2].clone()) {
-                        Ok(Some(old))
-                    } else {
-                        Ok(None)
-                    }
+                Ok(Box::new(move |db| {
+                    db.insert(split[1].clone(), split[2].clone())
                 }))
             }
         },
@@ -69,8 +112,8 @@ fn parse_command(string: String) -> Result<Command> {
             if split.len() != 2 {
                 Err(Box::new(error::ParseError))
             } else {
-                Ok(Box::new(move |map| {
-                    if let Some(old) = map.remove(&split[1]) {
+                Ok(Box::new(move |db| {
+                    if let Some(old) = db.remove(&split[1])? {
                         Ok(Some(old))
                     } else {
                         Err(Box::new(error::StorageError(format!("Key not found: {}", split[1]))))
@@ -84,17 +127,205 @@ fn parse_command(string: String) -> Result<Command> {
             if split.len() != 1 {
                 Err(Box::new(error::ParseError))
             } else {
-                Ok(Box::new(move |map| {
-                    map.clear();
+                Ok(Box::new(move |db| {
+                    db.clear()?;
+                    Ok(None)
+                }))
+            }
+        },
+
+        // Lists the retained versions of a key, oldest first, as alternating
+        // timestamps and values.
+        "HISTORY" => {
+            if split.len() != 2 {
+                Err(Box::new(error::ParseError))
+            } else {
+                Ok(Box::new(move |db| {
+                    if !db.history_enabled() {
+                        return Err(Box::new(error::StorageError("History is disabled".to_string())));
+                    }
+
+                    let versions = db.history(&split[1]);
+                    if versions.is_empty() {
+                        return Ok(None);
+                    }
+
+                    let fields: Vec<String> = versions.into_iter()
+                        .flat_map(|v| vec![v.since.to_string(), v.value])
+                        .collect();
+                    Ok(Some(fields.join(&SEP.to_string())))
+                }))
+            }
+        },
+
+        // Acquires a named lock for `owner` during `ttl` milliseconds and returns
+        // its fencing token. Fails if another owner holds an unexpired lease.
+        "LOCK" => {
+            if split.len() != 4 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let ttl: u64 = split[2].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    let token = db.locks.lock(&split[1], &split[3], ttl, now())?;
+                    Ok(Some(token.to_string()))
+                }))
+            }
+        },
+
+        // Releases a named lock. Fails if it is not held by `owner`.
+        "UNLOCK" => {
+            if split.len() != 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                Ok(Box::new(move |db| {
+                    db.locks.unlock(&split[1], &split[2], now())?;
                     Ok(None)
                 }))
             }
         },
 
+        // Extends the lease of a lock held by `owner` and returns its fencing token.
+        "REFRESH" => {
+            if split.len() != 4 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let ttl: u64 = split[3].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    let token = db.locks.refresh(&split[1], &split[2], ttl, now())?;
+                    Ok(Some(token.to_string()))
+                }))
+            }
+        },
+
+        // Returns the entries with `start <= key < end` in key order as alternating
+        // keys and values, at most `n` if `LIMIT n` is given. Requires an ordered storage engine.
+        "RANGE" => {
+            let limit = parse_limit(&split, 3)?;
+            Ok(Box::new(move |db| {
+                entries(db.range(&split[1], &split[2], limit))
+            }))
+        },
+
+        // Returns the entries whose key starts with the given prefix in key order,
+        // at most `n` if `LIMIT n` is given. Requires an ordered storage engine.
+        "PREFIX" => {
+            let limit = parse_limit(&split, 2)?;
+            Ok(Box::new(move |db| {
+                entries(db.prefix(&split[1], limit))
+            }))
+        },
+
+        // Appends a message to a queue and returns its id.
+        "ENQUEUE" => {
+            if split.len() != 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                Ok(Box::new(move |db| {
+                    let id = db.enqueue(&split[1], split[2].clone())?;
+                    Ok(Some(id.to_string()))
+                }))
+            }
+        },
+
+        // Returns the id and payload of the next visible message, which stays
+        // hidden for `visibility_timeout` milliseconds unless acknowledged.
+        "DEQUEUE" => {
+            if split.len() != 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let timeout: u64 = split[2].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    Ok(db.queues.dequeue(&split[1], timeout, now())
+                        .map(|m| format!("{}{}{}", m.id, SEP, m.payload)))
+                }))
+            }
+        },
+
+        // Acknowledges a dequeued message, removing it from the queue.
+        "ACK" => {
+            if split.len() != 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let id: u64 = split[2].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    db.queues.ack(&split[1], id, now())?;
+                    Ok(None)
+                }))
+            }
+        },
+
+        // Rejects a dequeued message, making it visible again immediately.
+        "NACK" => {
+            if split.len() != 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let id: u64 = split[2].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    db.queues.nack(&split[1], id, now())?;
+                    Ok(None)
+                }))
+            }
+        },
+
+        // Returns all entries of the database, ordered by key, in the given format.
+        "DUMP" => {
+            if split.len() != 2 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let format: args::Format = split[1].parse().map_err(|_| error::ParseError)?;
+                Ok(Box::new(move |db| {
+                    let data = transfer::encode(db.entries(), format)?;
+                    Ok(Some(data).filter(|data| !data.is_empty()))
+                }))
+            }
+        },
+
+        // Inserts the entries of data in the given format and returns their number.
+        // Nothing is inserted if any entry is malformed.
+        "RESTORE" => {
+            if split.len() < 3 {
+                Err(Box::new(error::ParseError))
+            } else {
+                let format: args::Format = split[1].parse().map_err(|_| error::ParseError)?;
+                let entries = transfer::decode(&split[2..].join(&SEP.to_string()), format)?;
+                Ok(Box::new(move |db| {
+                    let count = entries.len();
+                    for (key, value) in entries.clone() {
+                        db.insert(key, value)?;
+                    }
+                    Ok(Some(count.to_string()))
+                }))
+            }
+        },
+
         _ => Err(Box::new(error::ParseError))
     }
 }
 
+/// Parses a command with `args` fields, optionally followed by `LIMIT n`.
+fn parse_limit(split: &[String], args: usize) -> Result<usize> {
+    if split.len() == args {
+        Ok(usize::MAX)
+    } else if split.len() == args + 2 && split[args] == "LIMIT" {
+        Ok(split[args + 1].parse().map_err(|_| error::ParseError)?)
+    } else {
+        Err(Box::new(error::ParseError))
+    }
+}
+
+fn entries(entries: Option<Vec<(String, String)>>) -> Response {
+    let entries = entries.ok_or_else(|| {
+        error::StorageError("Range queries require an ordered storage engine".to_string())
+    })?;
+
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let fields: Vec<String> = entries.into_iter().flat_map(|(k, v)| vec![k, v]).collect();
+    Ok(Some(fields.join(&SEP.to_string())))
+}
+
 fn serialize(response: Response) -> String {
     match response {
         Ok(message) => {
@@ -115,25 +346,345 @@ fn serialize(response: Response) -> String {
     }
 }
 
-fn handle_request(stream: &mut TcpStream, map: Arc<CHashMap<String, String>>) -> Response {
+/// Splits off a leading `SELECT db` prefix and returns the selected database name
+/// together with the remaining command.
+fn select(string: String) -> Result<(String, String)> {
+    let prefix = format!("SELECT{}", SEP);
+    if !string.starts_with(&prefix) {
+        return Ok((DEFAULT_DB.to_string(), string));
+    }
+
+    let mut split = string[prefix.len()..].splitn(2, SEP);
+    match (split.next(), split.next()) {
+        (Some(name), Some(command)) if !name.is_empty() => Ok((name.to_string(), command.to_string())),
+        _ => Err(Box::new(error::ParseError))
+    }
+}
+
+/// Prefixes `request` with `AUTH password` if a password is given, for requests
+/// to other instances.
+fn authenticated(password: Option<&str>, request: &str) -> String {
+    match password {
+        Some(password) => format!("AUTH{}{}{}{}", SEP, password, SEP, request),
+        None => request.to_string()
+    }
+}
+
+/// The credentials a request is prefixed with.
+enum Credentials<'a> {
+    None,
+
+    // `AUTH password`, for the password set with requirepass
+    Password(&'a [u8]),
+
+    // `USER name password`, for a user of the ACL file
+    User(&'a [u8], &'a [u8])
+}
+
+/// Splits off a leading `AUTH password` or `USER name password` prefix and
+/// returns the credentials together with the remaining request.
+fn credentials(request: &[u8]) -> (Credentials<'_>, &[u8]) {
+    if let Some(rest) = request.strip_prefix(format!("AUTH{}", SEP).as_bytes()) {
+        let (password, rest) = field(rest);
+        (Credentials::Password(password), rest)
+    } else if let Some(rest) = request.strip_prefix(format!("USER{}", SEP).as_bytes()) {
+        let (name, rest) = field(rest);
+        let (password, rest) = field(rest);
+        (Credentials::User(name, password), rest)
+    } else {
+        (Credentials::None, request)
+    }
+}
+
+/// Splits `data` into its first field and the rest.
+fn field(data: &[u8]) -> (&[u8], &[u8]) {
+    let end = data.iter().position(|&b| char::from(b) == SEP).unwrap_or(data.len());
+    (&data[..end], data.get(end + 1..).unwrap_or_default())
+}
+
+/// Checks the credentials `request` is prefixed with and returns the user they
+/// belong to, or `None` for unrestricted access, with the rest of the request.
+fn authenticate(request: Vec<u8>, server: &Server, stream: &TcpStream) -> Result<(Option<Arc<acl::User>>, Vec<u8>)> {
+    let refuse = |message: &str| -> Result<(Option<Arc<acl::User>>, Vec<u8>)> {
+        Err(Box::new(error::StorageError(message.to_string())))
+    };
+    let peer = || stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+
+    match credentials(&request) {
+        (Credentials::None, _) if server.password.is_none() && server.acl.is_none() => Ok((None, request)),
+        (Credentials::None, _) => refuse("Authentication required"),
+
+        (Credentials::Password(given), rest) => match server.password {
+            Some(ref password) if acl::equal(password.as_bytes(), given) => Ok((None, rest.to_vec())),
+            Some(_) => {
+                warn!("Failed authentication attempt from {}", peer());
+                refuse("Invalid password")
+            },
+            None => refuse("No password is configured")
+        },
+
+        (Credentials::User(name, password), rest) => match server.acl {
+            Some(ref acl) => match acl.login(name, password) {
+                Some(user) => Ok((Some(user), rest.to_vec())),
+                None => {
+                    warn!("Failed authentication attempt as {} from {}", String::from_utf8_lossy(name), peer());
+                    refuse("Invalid username or password")
+                }
+            },
+            None => refuse("No ACL file is configured")
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, server: Arc<Server>) {
+    let addr = stream.peer_addr().ok();
+    let peer = addr.map(|a| a.to_string()).unwrap_or_default();
+    let request = read_request(&mut stream).and_then(|request| {
+        let authenticated = authenticate(request, &server, &stream);
+
+        // failed attempts are charged as well, to slow down guessing passwords.
+        // Only other nodes know the password, so requests between nodes are
+        // not charged if one is set
+        let node = matches!(authenticated, Ok((None, ref request)) if internal(request, &server));
+        if !(node && server.password.is_some()) {
+            server.limits.admit(addr.map(|a| a.ip()))?;
+            if let Ok((Some(ref user), _)) = authenticated {
+                server.limits.admit_user(&user.name)?;
+            }
+        }
+        authenticated
+    });
+    let response = match request {
+        // requests between cluster members may span several reads and require
+        // unrestricted access, like replication
+        Ok((None, ref request)) if request.starts_with(format!("{}{}", raft::RAFT, SEP).as_bytes()) => match server.raft {
+            Some(ref raft) => raft.handle(&mut stream, request.clone()),
+            None => Err(Box::new(error::StorageError("Not running in cluster mode".to_string())) as Box<dyn std::error::Error>)
+        },
+
+        request => {
+            let request = request.and_then(|(user, request)| {
+                let string = str::from_utf8(&request)?.trim_end_matches(char::from(0)).to_string();
+                debug!("{}", string);
+                Ok((user, string))
+            });
+
+            // the connection of a follower stays open to stream mutations
+            if let Ok((None, ref string)) = request {
+                if string.split(SEP).next() == Some(replication::SYNC) {
+                    server.replication.serve(stream, string, Arc::clone(&server.databases));
+                    return;
+                }
+            }
+
+            request.and_then(|(user, string)| handle_request(string, server, user, &peer))
+        }
+    };
+
+    if let Err(ref e) = response {
+        error!("{}", e);
+    }
+
+    if let Err(e) = write_response(&mut stream, response) {
+        error!("{}", e);
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>> {
     let mut buffer = [0; 524288];
-    stream.read(&mut buffer)?;
-    let string = str::from_utf8(&buffer[..])?
-        .trim_end_matches(char::from(0))
-        .to_string();
+    let len = stream.read(&mut buffer)?;
+    let mut request = buffer[..len].to_vec();
+
+    // the data of a restore may span several reads, up to the end of the stream,
+    // so a client that doesn't shut down its side must not hold a worker forever
+    if is_restore(&request) {
+        stream.set_read_timeout(Some(RESTORE_TIMEOUT))?;
+        if let Err(e) = stream.read_to_end(&mut request) {
+            return match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut =>
+                    Err(Box::new(error::StorageError("Timed out reading the data of RESTORE".to_string()))),
+                _ => Err(Box::new(e))
+            };
+        }
+    }
+    Ok(request)
+}
+
+/// Returns whether `request` is meant for this node by another node, for cluster
+/// membership, replication or to move an entry of a slot this node is importing.
+/// Such requests are not rate limited if they are authenticated by requirepass.
+fn internal(request: &[u8], server: &Server) -> bool {
+    let string = String::from_utf8_lossy(request).into_owned();
+    if string.starts_with(&format!("{}{}", raft::RAFT, SEP)) || string.split(SEP).next() == Some(replication::SYNC) {
+        return true;
+    }
+
+    let (asking, string) = asking(string);
+    let slots = match server.slots {
+        Some(ref slots) if asking => slots,
+        _ => return false
+    };
+    match select(string) {
+        Ok((_, command)) => {
+            let split: Vec<&str> = command.split(SEP).collect();
+            split.len() > 1 && KEY_COMMANDS.contains(&split[0]) && slots.imports(split[1])
+        },
+        Err(_) => false
+    }
+}
+
+fn is_restore(request: &[u8]) -> bool {
+    let (_, request) = credentials(request);
+    let string = String::from_utf8_lossy(&request[..request.len().min(1024)]).into_owned();
+    match select(string) {
+        Ok((_, command)) => command.starts_with(&format!("RESTORE{}", SEP)),
+        Err(_) => false
+    }
+}
+
+/// Splits off a leading `ASKING` prefix and returns whether it was present.
+fn asking(string: String) -> (bool, String) {
+    let prefix = format!("ASKING{}", SEP);
+    match string.strip_prefix(&prefix) {
+        Some(command) => (true, command.to_string()),
+        None => (false, string)
+    }
+}
+
+fn handle_request(string: String, server: Arc<Server>, user: Option<Arc<acl::User>>, client: &str) -> Response {
+    let (asking, string) = asking(string);
+    let (name, string) = select(string)?;
+    let admin = admin::parse_command(&string)?;
+    let is_admin = admin.is_some();
+    let audited = is_admin || WRITE_COMMANDS.contains(&string.split(SEP).next().unwrap_or_default());
+
+    let response = match user {
+        Some(ref user) => user.check(&string),
+        None => Ok(())
+    }.and_then(|_| match admin {
+        Some(command) => command(Arc::clone(&server)),
+        None => execute(&name, asking, &string, &server)
+    });
 
-    debug!("{}", string);
+    if let Some(ref audit) = server.audit {
+        if audited {
+            let user = user.as_ref().map(|user| user.name.as_str());
+            audit.record(client, user, &name, &string, is_admin, &response);
+        }
+    }
+    response
+}
+
+/// Returns whether `command` on `key` finds state on this node. Locks and queues
+/// are not moved by `MIGRATE`, so they are served here until the slot is handed
+/// over while they exist.
+fn stored(db: &Database, command: &str, key: &str) -> bool {
+    match command {
+        "LOCK" | "UNLOCK" | "REFRESH" => db.locks.held(key, now()),
+        "ENQUEUE" | "DEQUEUE" | "ACK" | "NACK" => db.queues.contains(key),
+        _ => db.get(key).is_some()
+    }
+}
+
+fn execute(name: &str, asking: bool, string: &str, server: &Server) -> Response {
+    // held while the command runs if its slot is being migrated
+    let split: Vec<&str> = string.split(SEP).collect();
+    let _migration = match server.slots {
+        Some(ref slots) if split.len() > 1 && KEY_COMMANDS.contains(&split[0]) => {
+            slots.route(split[1], asking, || stored(&server.databases.read(name), split[0], split[1]))?
+        },
+        _ => None
+    };
+
+    if WRITE_COMMANDS.contains(&split[0]) {
+        server.writable()?;
+    }
+
+    if let Some(leader) = server.replication.leader() {
+        if WRITE_COMMANDS.contains(&split[0]) {
+            return Err(Box::new(error::StorageError(format!("Read-only replica of {}", leader))));
+        }
+    }
 
-    let command: Command = parse_command(string)?;
-    command(map)
+    if let Some(ref raft) = server.raft {
+        match parse_record(name, string)? {
+            Some(record) => return raft.propose(record),
+            None if WRITE_COMMANDS.contains(&split[0]) => {
+                return Err(Box::new(error::StorageError("Not available in cluster mode".to_string())));
+            },
+            None => raft.read_barrier()?
+        }
+    }
+
+    if let Some(ref slots) = server.slots {
+        match split[0] {
+            // every restored entry has to belong to a slot of this node
+            "RESTORE" if split.len() > 2 => {
+                let format: args::Format = split[1].parse().map_err(|_| error::ParseError)?;
+                for (key, _) in transfer::decode(&split[2..].join(&SEP.to_string()), format)? {
+                    slots.owns(&key)?;
+                }
+            },
+            "RANGE" | "PREFIX" => return served_entries(name, string, server, slots),
+            _ => ()
+        }
+    }
+
+    let command: Command = parse_command(string.to_string())?;
+    // reads do not create the database, so files of persistent engines are
+    // only created by writes
+    let db = if WRITE_COMMANDS.contains(&split[0]) {
+        server.databases.get(name)?
+    } else {
+        server.databases.read(name)
+    };
+    command(db)
+}
+
+/// Answers a `RANGE` or `PREFIX` query with sharded keys, leaving out entries
+/// stored here of slots served by other nodes.
+fn served_entries(name: &str, string: &str, server: &Server, slots: &slots::Slots) -> Response {
+    let split: Vec<String> = string.split(SEP).map(|s| s.to_string()).collect();
+    let db = server.databases.read(name);
+    let (found, limit) = if split[0] == "RANGE" {
+        let limit = parse_limit(&split, 3)?;
+        (db.range(&split[1], &split[2], usize::MAX), limit)
+    } else {
+        let limit = parse_limit(&split, 2)?;
+        (db.prefix(&split[1], usize::MAX), limit)
+    };
+
+    entries(found.map(|found| found.into_iter().filter(|(key, _)| slots.serves(key)).take(limit).collect()))
+}
+
+/// Returns the record a write command is replicated as in cluster mode, or
+/// `None` if the command is not replicated.
+fn parse_record(db: &str, string: &str) -> Result<Option<aof::Record>> {
+    let split: Vec<&str> = string.split(SEP).collect();
+    let db = db.to_string();
+
+    let record = match split[..] {
+        ["INSERT", key, value] => aof::Record::Insert { db, key: key.to_string(), value: value.to_string() },
+        ["REMOVE", key] => aof::Record::Remove { db, key: key.to_string() },
+        ["CLEAR"] => aof::Record::Clear { db },
+        ["INSERT", ..] | ["REMOVE", ..] | ["CLEAR", ..] => return Err(Box::new(error::ParseError)),
+        _ => return Ok(None)
+    };
+    Ok(Some(record))
 }
 
 fn write_response(stream: &mut TcpStream, response: Response) -> Result<()> {
-    stream.write(serialize(response).as_bytes())?;
+    stream.write_all(serialize(response).as_bytes())?;
     stream.flush()?;
     Ok(())
 }
 
+/// Returns whether connections from `ip` are accepted.
+fn permitted(config: &args::Config, ip: IpAddr) -> bool {
+    !config.deny.iter().any(|cidr| cidr.contains(ip))
+        && (config.allow.is_empty() || config.allow.iter().any(|cidr| cidr.contains(ip)))
+}
+
 /// Runs a new instance of yocto
 ///
 /// # Arguments
@@ -153,7 +704,48 @@ pub fn run(config: args::Config) {
         }
     };
 
-    let map: Arc<CHashMap<String, String>> = Arc::new(CHashMap::new());
+    let server = match Server::new(&config) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!("Failed to initialize: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = server.load() {
+        error!("Failed to restore data: {}", e);
+        process::exit(1);
+    }
+
+    if let Some(ref raft) = server.raft {
+        raft.start();
+    }
+
+    if let Some(ref leader) = config.replica_of {
+        server.replication.follow(leader.clone(), Arc::clone(&server.databases));
+    }
+
+    if config.aof.is_some() && config.aof_rewrite_ratio > 0.0 {
+        let server = Arc::clone(&server);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if server.needs_rewrite() {
+                if let Err(e) = Arc::clone(&server).bgrewrite() {
+                    error!("Unable to start log rewrite: {}", e);
+                }
+            }
+        });
+    }
+
+    if let Some(interval) = config.save_interval {
+        let server = Arc::clone(&server);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            if let Err(e) = Arc::clone(&server).bgsave() {
+                error!("Unable to start scheduled snapshot: {}", e);
+            }
+        });
+    }
 
     let pool = threadp::ThreadPool::new(config.threads);
 
@@ -168,18 +760,23 @@ pub fn run(config: args::Config) {
 
     for stream in iter {
         match stream {
-            Ok(mut stream) => {
-                let map = Arc::clone(&map);
-                pool.assign(move || {
-                    let response = handle_request(&mut stream, map);
-
-                    if let Err(e) = write_response(&mut stream, if let Err(e) = response {
-                        error!("{}", e);
-                        Err(e)
-                    } else { response }) {
-                        error!("{}", e);
-                    }
-                });
+            Ok(stream) => {
+                match stream.peer_addr() {
+                    Ok(peer) if !permitted(&config, peer.ip()) => {
+                        warn!("Refused connection from {}", peer);
+                        continue;
+                    },
+
+                    // a client that cannot be identified is not let past the lists
+                    Err(e) if !config.allow.is_empty() || !config.deny.is_empty() => {
+                        warn!("Refused connection from an unknown address: {}", e);
+                        continue;
+                    },
+                    _ => ()
+                }
+
+                let server = Arc::clone(&server);
+                pool.assign(move || handle_connection(stream, server));
             },
 
             Err(e) => {
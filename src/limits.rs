@@ -0,0 +1,104 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Requests are rate limited by token buckets, each holding up to a second's
+// worth of requests and refilling continuously. Requests of clients over their
+// limit are rejected once they have been read, while requests between nodes of
+// a cluster are never charged.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use crate::args::Config;
+use crate::{error, Result};
+
+// buckets are only pruned once there are this many
+const PRUNE_AT: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+/// Token buckets allowing `rate` requests per second for each key.
+struct Limiter<K> {
+    rate: u32,
+    buckets: Mutex<HashMap<K, Bucket>>
+}
+
+impl<K: Hash + Eq> Limiter<K> {
+    fn new(rate: u32) -> Limiter<K> {
+        Limiter { rate, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token from the bucket of `key` and returns whether there was one.
+    fn take(&self, key: K) -> bool {
+        let now = Instant::now();
+        let rate = f64::from(self.rate);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // full buckets are no different from missing ones
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < rate);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: rate, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// The configured rate limits.
+pub struct Limits {
+    client: Option<Limiter<IpAddr>>,
+    user: Option<Limiter<String>>,
+    global: Option<Limiter<()>>
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Limits {
+        Limits {
+            client: config.client_rate_limit.map(Limiter::new),
+            user: config.user_rate_limit.map(Limiter::new),
+            global: config.rate_limit.map(Limiter::new)
+        }
+    }
+
+    /// Fails if the client at `ip` or all clients together are over their limit.
+    /// A client whose address is unknown is refused if clients are limited.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<()> {
+        if let Some(ref limiter) = self.client {
+            let ip = ip.ok_or_else(|| error::StorageError("Unknown client address".to_string()))?;
+            if !limiter.take(ip) {
+                return Err(Box::new(error::RateLimitError { scope: format!("Client {}", ip), rate: limiter.rate }));
+            }
+        }
+
+        if let Some(ref limiter) = self.global {
+            if !limiter.take(()) {
+                return Err(Box::new(error::RateLimitError { scope: "Server".to_string(), rate: limiter.rate }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the authenticated user `name` is over its limit.
+    pub fn admit_user(&self, name: &str) -> Result<()> {
+        if let Some(ref limiter) = self.user {
+            if !limiter.take(name.to_string()) {
+                return Err(Box::new(error::RateLimitError { scope: format!("User {}", name), rate: limiter.rate }));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chashmap::CHashMap;
+use crate::memory::{self, Memory};
+use crate::{error, Result};
+
+// bits below the start time in milliseconds that fencing tokens are counted in
+const TOKEN_SHIFT: u32 = 20;
+
+/// A lock grant, valid until `expires` unless refreshed.
+struct Lease {
+    owner: String,
+    token: u64,
+    expires: u64
+}
+
+/// Named leases with fencing tokens. Expired leases are treated as free the next
+/// time the lock is accessed, so a lock held by a dead client is released once
+/// its ttl has passed.
+pub struct Locks {
+    next_token: AtomicU64,
+    leases: CHashMap<String, Lease>,
+    // charged for the leases
+    memory: Option<Arc<Memory>>
+}
+
+impl Locks {
+    pub fn new(memory: Option<Arc<Memory>>) -> Locks {
+        Locks {
+            // later than any token handed out by an earlier run, unless it
+            // granted more than 2^20 leases per millisecond it ran
+            next_token: AtomicU64::new((crate::now() << TOKEN_SHIFT).max(1)),
+            leases: CHashMap::new(),
+            memory
+        }
+    }
+
+    /// Grants `name` to `owner` for `ttl` milliseconds and returns the fencing token.
+    /// Every new grant gets a token greater than all tokens handed out before. If
+    /// `owner` already holds the lock, the lease is extended and the token kept.
+    pub fn lock(&self, name: &str, owner: &str, ttl: u64, now: u64) -> Result<u64> {
+        let mut result = Err(held(name));
+
+        self.leases.alter(name.to_string(), |lease| {
+            match lease {
+                Some(mut lease) => {
+                    if lease.owner == owner {
+                        lease.expires = now.saturating_add(ttl);
+                        result = Ok(lease.token);
+                    } else if lease.expires <= now {
+                        self.release(name, &lease);
+                        lease = self.grant(name, owner, ttl, now);
+                        result = Ok(lease.token);
+                    }
+                    Some(lease)
+                },
+
+                None => {
+                    let lease = self.grant(name, owner, ttl, now);
+                    result = Ok(lease.token);
+                    Some(lease)
+                }
+            }
+        });
+
+        result
+    }
+
+    /// Releases `name` if it is held by `owner`.
+    pub fn unlock(&self, name: &str, owner: &str, now: u64) -> Result<()> {
+        let mut result = Err(not_held(name, owner));
+
+        self.leases.alter(name.to_string(), |lease| {
+            match lease {
+                Some(ref l) if l.owner == owner && l.expires > now => {
+                    self.release(name, l);
+                    result = Ok(());
+                    None
+                },
+                Some(ref l) if l.expires <= now => {
+                    self.release(name, l);
+                    None
+                },
+                other => other
+            }
+        });
+
+        result
+    }
+
+    /// Extends the lease on `name` held by `owner` to `ttl` milliseconds from now
+    /// and returns its fencing token.
+    pub fn refresh(&self, name: &str, owner: &str, ttl: u64, now: u64) -> Result<u64> {
+        match self.leases.get_mut(name) {
+            Some(ref mut lease) if lease.owner == owner && lease.expires > now => {
+                lease.expires = now.saturating_add(ttl);
+                Ok(lease.token)
+            },
+            _ => Err(not_held(name, owner))
+        }
+    }
+
+    /// Returns whether `name` is held by anyone.
+    pub fn held(&self, name: &str, now: u64) -> bool {
+        self.leases.get(name).is_some_and(|lease| lease.expires > now)
+    }
+
+    fn grant(&self, name: &str, owner: &str, ttl: u64, now: u64) -> Lease {
+        if let Some(ref memory) = self.memory {
+            memory.charge(memory::lease_size(name, owner));
+        }
+        Lease {
+            owner: owner.to_string(),
+            token: self.next_token.fetch_add(1, Ordering::SeqCst),
+            expires: now.saturating_add(ttl)
+        }
+    }
+
+    fn release(&self, name: &str, lease: &Lease) {
+        if let Some(ref memory) = self.memory {
+            memory.release(memory::lease_size(name, &lease.owner));
+        }
+    }
+}
+
+fn held(name: &str) -> Box<dyn std::error::Error> {
+    Box::new(error::StorageError(format!("Lock is held by another owner: {}", name)))
+}
+
+fn not_held(name: &str, owner: &str) -> Box<dyn std::error::Error> {
+    Box::new(error::StorageError(format!("Lock {} is not held by {}", name, owner)))
+}
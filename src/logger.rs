@@ -44,7 +44,7 @@ impl Destination {
 
 impl Destination {
     /// Returns a `Write` corresponding to the `Destination`.
-    fn write(&self) -> Box<Write> {
+    fn write(&self) -> Box<dyn Write> {
         match *self {
             Destination::Stdout => Box::new(stdout()),
             Destination::Stderr => Box::new(stderr()),
@@ -52,6 +52,7 @@ impl Destination {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for Destination {
     fn default() -> Destination {
         Destination::Stderr
@@ -98,7 +99,7 @@ impl Logger {
         loop {
             let old = self.max_module_width.load(Ordering::SeqCst);
             let new = max(old, width);
-            if self.max_module_width.compare_and_swap(old, new, Ordering::SeqCst) == old {
+            if self.max_module_width.compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
                 return new;
             }
         }
@@ -108,7 +109,7 @@ impl Logger {
         loop {
             let old = self.max_target_width.load(Ordering::SeqCst);
             let new = max(old, width);
-            if self.max_target_width.compare_and_swap(old, new, Ordering::SeqCst) == old {
+            if self.max_target_width.compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
                 return new;
             }
         }
@@ -141,13 +142,13 @@ impl Log for Logger {
 
         let module = record.location().module_path();
         let target = record.target();
-        let module_length = self.update_module_width(module.graphemes(true).count());
+        let _module_length = self.update_module_width(module.graphemes(true).count());
 
         let _ = if module == target {
             writeln!(self.destination.write(), "{}| {}",
                 self.theme.paint_log_level(record.level()), record.args())
         } else {
-            let target_length = self.update_target_width(target.graphemes(true).count());
+            let _target_length = self.update_target_width(target.graphemes(true).count());
             writeln!(self.destination.write(), "{}| {}",
                 self.theme.paint_log_level(record.level()), record.args())
         };
@@ -232,6 +233,7 @@ pub fn init(destination: Destination, level: LogLevelFilter, theme: Theme) -> Re
 
 /// Initializes the global logger to log at the given level, using the defaults
 /// for other fields.
+#[allow(clippy::field_reassign_with_default)]
 pub fn init_level(level: LogLevelFilter) -> Result<(), SetLoggerError> {
     platform_init();
     let mut logger = Logger::default();
@@ -3,10 +3,41 @@
 // Released under the MIT license.
 //
 
-use yocto::{args, logo, logger};
+use std::process;
+use yocto::{args, logo, logger, transfer};
+use yocto::args::Action;
 
 fn main() {
-    let config = args::get();
+    let action = match args::get() {
+        Ok(action) => action,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let config = match action {
+        Action::Serve(config) => *config,
+
+        Action::Export(t) => {
+            if let Err(e) = transfer::export(&t) {
+                eprintln!("Export failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+
+        Action::Import(t) => {
+            match transfer::import(&t) {
+                Ok(count) => eprintln!("Imported {} entries", count),
+                Err(e) => {
+                    eprintln!("Import failed: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+    };
 
     print!("{}", logo::LOGO);
     println!(" yocto {} - (c) 2019\n", env!("CARGO_PKG_VERSION"));
@@ -0,0 +1,222 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crate::args::Eviction;
+use crate::{error, Result};
+
+// estimated bookkeeping cost of a stored entry on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+// estimated bookkeeping cost of a retained version on top of its value
+const VERSION_OVERHEAD: usize = 48;
+
+// estimated bookkeeping cost of a queued message on top of its payload
+const MESSAGE_OVERHEAD: usize = 48;
+
+// estimated bookkeeping cost of a lease on top of the lock name and owner
+const LEASE_OVERHEAD: usize = 64;
+
+// number of random entries compared when looking for an entry to evict
+const SAMPLES: usize = 5;
+
+/// Returns the number of bytes accounted for an entry.
+pub fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// Returns the number of bytes accounted for a version kept in the history.
+pub fn version_size(value: &str) -> usize {
+    value.len() + VERSION_OVERHEAD
+}
+
+/// Returns the number of bytes accounted for a queued message.
+pub fn message_size(payload: &str) -> usize {
+    payload.len() + MESSAGE_OVERHEAD
+}
+
+/// Returns the number of bytes accounted for a lease.
+pub fn lease_size(name: &str, owner: &str) -> usize {
+    name.len() + owner.len() + LEASE_OVERHEAD
+}
+
+struct Usage {
+    size: usize,
+    // position in `Tracker::slots`
+    slot: usize,
+    // updated on reads without locking the tracker exclusively
+    last_access: AtomicU64,
+    hits: AtomicU32
+}
+
+#[derive(Default)]
+struct Tracker {
+    used: usize,
+    rng: u64,
+    // all tracked entries as (database, key), for sampling in constant time
+    slots: Vec<(String, String)>,
+    usage: HashMap<(String, String), Usage>
+}
+
+impl Tracker {
+    /// Returns a random tracked entry, or `None` if memory is only charged by
+    /// the history, queued messages and locks.
+    fn sample(&mut self) -> Option<(String, String)> {
+        if self.slots.is_empty() {
+            return None;
+        }
+
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        Some(self.slots[(self.rng % self.slots.len() as u64) as usize].clone())
+    }
+
+    fn remove(&mut self, id: &(String, String)) {
+        if let Some(usage) = self.usage.remove(id) {
+            self.used -= usage.size;
+            self.slots.swap_remove(usage.slot);
+            if let Some(moved) = self.slots.get(usage.slot).cloned() {
+                self.usage.get_mut(&moved).unwrap().slot = usage.slot;
+            }
+        }
+    }
+}
+
+/// Accounts the memory used by all databases against a limit and picks entries
+/// to evict once it is reached. Entries are tracked individually, while the
+/// history, queued messages and locks are charged as a whole and never evicted.
+pub struct Memory {
+    limit: usize,
+    policy: Eviction,
+    clock: AtomicU64,
+    // bytes charged for everything but entries
+    charged: AtomicUsize,
+    tracker: RwLock<Tracker>
+}
+
+impl Memory {
+    pub fn new(limit: usize, policy: Eviction) -> Memory {
+        Memory {
+            limit,
+            policy,
+            clock: AtomicU64::new(0),
+            charged: AtomicUsize::new(0),
+            tracker: RwLock::new(Tracker { rng: crate::now() | 1, ..Tracker::default() })
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.tracker.read().unwrap().used + self.charged.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Records an access to `key` in `db`.
+    pub fn touch(&self, db: &str, key: &str) {
+        let tracker = self.tracker.read().unwrap();
+        if let Some(usage) = tracker.usage.get(&(db.to_string(), key.to_string())) {
+            usage.last_access.store(self.tick(), Ordering::Relaxed);
+            let _ = usage.hits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hits| hits.checked_add(1));
+        }
+    }
+
+    /// Checks whether storing `size` bytes, at `key` in `db` if given, fits into
+    /// the limit. Returns `None` if it does, or the entry to evict to make room
+    /// otherwise. Fails if the policy forbids eviction or nothing can be evicted.
+    pub fn victim(&self, db: &str, key: Option<&str>, size: usize) -> Result<Option<(String, String)>> {
+        let mut tracker = self.tracker.write().unwrap();
+        let id = key.map(|key| (db.to_string(), key.to_string()));
+        let old = id.as_ref().and_then(|id| tracker.usage.get(id)).map_or(0, |usage| usage.size);
+        let used = tracker.used + self.charged.load(Ordering::SeqCst);
+
+        if used - old + size <= self.limit {
+            return Ok(None);
+        }
+
+        if size > self.limit {
+            return Err(out_of_memory());
+        }
+
+        let candidates: Vec<(String, String)> = match self.policy {
+            Eviction::NoEviction | Eviction::VolatileTtl => return Err(out_of_memory()),
+            Eviction::Random => tracker.sample().into_iter().collect(),
+            Eviction::AllKeysLru | Eviction::AllKeysLfu if tracker.slots.len() <= SAMPLES => tracker.slots.clone(),
+            Eviction::AllKeysLru | Eviction::AllKeysLfu => (0..SAMPLES).filter_map(|_| tracker.sample()).collect()
+        };
+
+        let victim = candidates.into_iter()
+            .filter(|candidate| Some(candidate) != id.as_ref())
+            .min_by_key(|candidate| {
+                let usage = &tracker.usage[candidate];
+                let last_access = usage.last_access.load(Ordering::Relaxed);
+                match self.policy {
+                    Eviction::AllKeysLfu => (u64::from(usage.hits.load(Ordering::Relaxed)), last_access),
+                    _ => (last_access, 0)
+                }
+            })
+            // the samples only hit the entry being written
+            .or_else(|| tracker.slots.iter().find(|slot| Some(*slot) != id.as_ref()).cloned());
+
+        victim.map(Some).ok_or_else(out_of_memory)
+    }
+
+    /// Accounts `size` bytes for `key` in `db`, replacing its previous size.
+    pub fn insert(&self, db: &str, key: &str, size: usize) {
+        let clock = self.tick();
+        let mut tracker = self.tracker.write().unwrap();
+        let id = (db.to_string(), key.to_string());
+
+        let old = match tracker.usage.get_mut(&id) {
+            Some(usage) => {
+                let old = usage.size;
+                usage.size = size;
+                usage.last_access.store(clock, Ordering::Relaxed);
+                old
+            },
+            None => {
+                let slot = tracker.slots.len();
+                tracker.slots.push(id.clone());
+                tracker.usage.insert(id, Usage {
+                    size,
+                    slot,
+                    last_access: AtomicU64::new(clock),
+                    hits: AtomicU32::new(0)
+                });
+                0
+            }
+        };
+
+        tracker.used = tracker.used - old + size;
+    }
+
+    /// Stops accounting `key` in `db`.
+    pub fn remove(&self, db: &str, key: &str) {
+        self.tracker.write().unwrap().remove(&(db.to_string(), key.to_string()));
+    }
+
+    /// Accounts `size` bytes that cannot be evicted.
+    pub fn charge(&self, size: usize) {
+        self.charged.fetch_add(size, Ordering::SeqCst);
+    }
+
+    /// Stops accounting `size` bytes charged before.
+    pub fn release(&self, size: usize) {
+        self.charged.fetch_sub(size, Ordering::SeqCst);
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+fn out_of_memory() -> Box<dyn std::error::Error> {
+    Box::new(error::OutOfMemoryError)
+}
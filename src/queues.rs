@@ -0,0 +1,118 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chashmap::CHashMap;
+use crate::memory::{self, Memory};
+use crate::{error, Result};
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: u64,
+    pub payload: String
+}
+
+#[derive(Default)]
+struct Queue {
+    ready: VecDeque<Message>,
+    // messages handed out but not yet acknowledged, with their visibility deadline
+    pending: HashMap<u64, (Message, u64)>
+}
+
+impl Queue {
+    /// Makes messages whose visibility timeout has passed available again, ahead
+    /// of newer messages.
+    fn restore_expired(&mut self, now: u64) {
+        let mut expired: Vec<u64> = self.pending.iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort_unstable_by(|a, b| b.cmp(a));
+
+        for id in expired {
+            if let Some((message, _)) = self.pending.remove(&id) {
+                self.ready.push_front(message);
+            }
+        }
+    }
+}
+
+/// Named queues with at-least-once delivery. A dequeued message stays invisible
+/// for the given timeout and is delivered again unless it is acknowledged.
+pub struct Queues {
+    next_id: AtomicU64,
+    queues: CHashMap<String, Queue>,
+    // charged for the messages until they are acknowledged
+    memory: Option<Arc<Memory>>
+}
+
+impl Queues {
+    pub fn new(memory: Option<Arc<Memory>>) -> Queues {
+        Queues {
+            next_id: AtomicU64::new(1),
+            queues: CHashMap::new(),
+            memory
+        }
+    }
+
+    /// Appends `payload` to `queue` and returns the new message id.
+    pub fn enqueue(&self, queue: &str, payload: String) -> u64 {
+        if let Some(ref memory) = self.memory {
+            memory.charge(memory::message_size(&payload));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let message = Message { id, payload };
+
+        self.queues.upsert(queue.to_string(), || {
+            let mut q = Queue::default();
+            q.ready.push_back(message.clone());
+            q
+        }, |q| q.ready.push_back(message.clone()));
+
+        id
+    }
+
+    /// Hands out the oldest visible message of `queue` and hides it for `timeout`
+    /// milliseconds.
+    pub fn dequeue(&self, queue: &str, timeout: u64, now: u64) -> Option<Message> {
+        let mut q = self.queues.get_mut(queue)?;
+        q.restore_expired(now);
+
+        let message = q.ready.pop_front()?;
+        q.pending.insert(message.id, (message.clone(), now.saturating_add(timeout)));
+        Some(message)
+    }
+
+    /// Returns whether `queue` holds any messages, delivered or not.
+    pub fn contains(&self, queue: &str) -> bool {
+        self.queues.get(queue).is_some_and(|q| !q.ready.is_empty() || !q.pending.is_empty())
+    }
+
+    /// Deletes a delivered message for good.
+    pub fn ack(&self, queue: &str, id: u64, now: u64) -> Result<()> {
+        let mut q = self.queues.get_mut(queue).ok_or_else(|| unknown(queue, id))?;
+        q.restore_expired(now);
+        let (message, _) = q.pending.remove(&id).ok_or_else(|| unknown(queue, id))?;
+        if let Some(ref memory) = self.memory {
+            memory.release(memory::message_size(&message.payload));
+        }
+        Ok(())
+    }
+
+    /// Returns a delivered message to the front of its queue right away.
+    pub fn nack(&self, queue: &str, id: u64, now: u64) -> Result<()> {
+        let mut q = self.queues.get_mut(queue).ok_or_else(|| unknown(queue, id))?;
+        q.restore_expired(now);
+        let (message, _) = q.pending.remove(&id).ok_or_else(|| unknown(queue, id))?;
+        q.ready.push_front(message);
+        Ok(())
+    }
+}
+
+fn unknown(queue: &str, id: u64) -> Box<dyn std::error::Error> {
+    Box::new(error::StorageError(format!("No pending message {} in queue {}", id, queue)))
+}
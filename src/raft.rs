@@ -0,0 +1,813 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Cluster mode runs the Raft consensus algorithm across a fixed set of members.
+// Writes are appended to the log of the elected leader and only acknowledged
+// once a majority of members has stored them, after which every member applies
+// them in log order. Reads are served by the leader once it has confirmed with
+// a majority that it still leads and has applied everything committed at that
+// time, which makes them linearizable.
+//
+// Members talk to each other through the regular port, one RPC per connection.
+// A request starts with RAFT, is closed for writing once sent and is answered
+// like any other command:
+//
+//     RAFT VOTE term candidate last_index last_term -> term granted
+//     RAFT APPEND term leader prev_index prev_term commit n entries... -> term success index
+//
+// Each entry is its term followed by N for the no-op a new leader starts its
+// term with, I db key value, R db key or C db. On failure, the index of an
+// append answer hints where the follower's log ends or diverges.
+//
+// The term, vote and log are kept in a directory and synced to disk before
+// being relied upon. The log is never compacted.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::result;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::aof::Record;
+use crate::codec::{frame, frame_after, invalid, FrameError, Reader};
+use crate::db::Databases;
+use crate::{error, Response, Result, SEP};
+
+/// The first field of a request between members.
+pub const RAFT: &str = "RAFT";
+const VOTE: &str = "VOTE";
+const APPEND: &str = "APPEND";
+
+// an election starts after hearing nothing from a leader for this long, plus
+// a random amount of up to the same
+const ELECTION_TIMEOUT: u64 = 300;
+const HEARTBEAT: Duration = Duration::from_millis(50);
+const RPC_TIMEOUT: Duration = Duration::from_millis(250);
+// how long clients wait for their write to be committed or read to be confirmed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// maximum number of entries sent per append
+const BATCH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    term: u64,
+    // `None` for the no-op a leader commits at the start of its term
+    record: Option<Record>
+}
+
+impl Entry {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = self.term.to_le_bytes().to_vec();
+        match self.record {
+            Some(ref record) => {
+                payload.push(1);
+                payload.extend_from_slice(&record.encode()?);
+            },
+            None => payload.push(0)
+        }
+        Ok(payload)
+    }
+
+    fn decode(payload: &[u8]) -> io::Result<Entry> {
+        let mut reader = Reader::new(payload, 0);
+        let term = reader.u64()?;
+        let record = match reader.u8()? {
+            0 => None,
+            1 => Some(Record::decode(reader.frame().map_err(|e| invalid(&format!("{:?} error in entry", e)))?)?),
+            _ => return Err(invalid("Unknown entry type"))
+        };
+        Ok(Entry { term, record })
+    }
+
+    fn write_fields(&self, fields: &mut Vec<String>) {
+        fields.push(self.term.to_string());
+        match self.record {
+            None => fields.push("N".to_string()),
+            Some(Record::Insert { ref db, ref key, ref value }) =>
+                fields.extend(vec!["I".to_string(), db.clone(), key.clone(), value.clone()]),
+            Some(Record::Remove { ref db, ref key }) =>
+                fields.extend(vec!["R".to_string(), db.clone(), key.clone()]),
+            Some(Record::Clear { ref db }) =>
+                fields.extend(vec!["C".to_string(), db.clone()])
+        }
+    }
+
+    fn read_fields(fields: &mut Fields) -> Result<Entry> {
+        let term = fields.number()?;
+        let record = match fields.next()? {
+            "N" => None,
+            "I" => Some(Record::Insert { db: fields.string()?, key: fields.string()?, value: fields.string()? }),
+            "R" => Some(Record::Remove { db: fields.string()?, key: fields.string()? }),
+            "C" => Some(Record::Clear { db: fields.string()? }),
+            _ => return Err(Box::new(error::ParseError))
+        };
+        Ok(Entry { term, record })
+    }
+}
+
+/// The fields of a request or answer.
+struct Fields<'a>(std::str::Split<'a, char>);
+
+impl<'a> Fields<'a> {
+    fn new(string: &'a str) -> Fields<'a> {
+        Fields(string.split(SEP))
+    }
+
+    fn next(&mut self) -> Result<&'a str> {
+        self.0.next().ok_or_else(|| Box::new(error::ParseError) as Box<dyn std::error::Error>)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(self.next()?.to_string())
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        Ok(self.next()?.parse().map_err(|_| error::ParseError)?)
+    }
+}
+
+/// The term, vote and log of a member on disk.
+struct Disk {
+    dir: String,
+    log: File,
+    // position of every entry in the log file, starting at index 1
+    offsets: Vec<u64>,
+    size: u64
+}
+
+impl Disk {
+    /// Opens the state in `dir`, creating it if necessary, and returns it with
+    /// the stored term, vote and entries. An entry torn by a crash at the end of
+    /// the log is truncated, any other corruption is an error.
+    fn open(dir: &str) -> io::Result<(Disk, u64, Option<String>, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+
+        let (term, voted_for) = match fs::read_to_string(Path::new(dir).join("raft.meta")) {
+            Ok(meta) => {
+                let mut lines = meta.lines();
+                let term = lines.next().unwrap_or_default().parse().map_err(|_| invalid("Invalid Raft term"))?;
+                (term, lines.next().filter(|vote| !vote.is_empty()).map(|vote| vote.to_string()))
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e)
+        };
+
+        let path = Path::new(dir).join("raft.log");
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e)
+        };
+
+        let mut reader = Reader::new(&data, 0);
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        while reader.remaining() > 0 {
+            let offset = reader.position();
+            let error = match reader.frame() {
+                Ok(payload) => match Entry::decode(payload) {
+                    Ok(entry) => {
+                        entries.push(entry);
+                        offsets.push(offset as u64);
+                        continue;
+                    },
+                    Err(e) => format!("undecodable entry ({})", e)
+                },
+
+                // only the last entry may have been partially written, as entries
+                // are acknowledged once synced
+                Err(FrameError::Truncated) if !frame_after(&data, offset, |payload| Entry::decode(payload).is_ok()) => {
+                    warn!("Raft log {} ends with a torn entry at offset {}, truncating {} bytes",
+                        path.display(), offset, data.len() - offset);
+                    reader = Reader::new(&data, offset);
+                    break;
+                },
+                Err(FrameError::Checksum) if reader.remaining() == 0 => {
+                    warn!("Raft log {} ends with a torn entry at offset {} (checksum mismatch), truncating {} bytes",
+                        path.display(), offset, data.len() - offset);
+                    reader = Reader::new(&data, offset);
+                    break;
+                },
+
+                Err(FrameError::Truncated) => "length exceeds the end of the log, but intact entries follow".to_string(),
+                Err(FrameError::Checksum) => "checksum mismatch".to_string()
+            };
+
+            // entries after this one may have been committed, so none are dropped
+            error!("Raft log {} is corrupt at offset {} (entry {}): {}", path.display(), offset, entries.len() + 1,
+                error);
+            return Err(invalid(&format!("Corrupt Raft log {} at offset {}", path.display(), offset)));
+        }
+
+        let size = reader.position() as u64;
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        log.set_len(size)?;
+
+        Ok((Disk { dir: dir.to_string(), log, offsets, size }, term, voted_for, entries))
+    }
+
+    fn save_meta(&self, term: u64, voted_for: &Option<String>) -> io::Result<()> {
+        let path = Path::new(&self.dir).join("raft.meta");
+        let tmp = Path::new(&self.dir).join("raft.meta.tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(format!("{}\n{}\n", term, voted_for.as_deref().unwrap_or_default()).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let mut data = Vec::new();
+        for entry in entries {
+            self.offsets.push(self.size + data.len() as u64);
+            data.extend_from_slice(&frame(&entry.encode()?));
+        }
+
+        self.log.write_all(&data)?;
+        self.log.sync_data()?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Removes the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        let size = self.offsets[index as usize - 1];
+        self.log.set_len(size)?;
+        self.log.sync_data()?;
+        self.offsets.truncate(index as usize - 1);
+        self.size = size;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader
+}
+
+type Waiter = Sender<result::Result<Option<String>, String>>;
+
+struct State {
+    term: u64,
+    voted_for: Option<String>,
+    // `log[0]` is a placeholder, so that entries start at index 1
+    log: Vec<Entry>,
+    disk: Option<Disk>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<String>,
+    deadline: Instant,
+    rng: u64,
+    votes: usize,
+    // per member, the next entry to send and the last entry known to be stored
+    next: HashMap<String, u64>,
+    matched: HashMap<String, u64>,
+    // confirmation rounds for reads: the latest one started, and per member
+    // the latest one it answered
+    round: u64,
+    acked: HashMap<String, u64>,
+    // clients waiting for the entry at an index, with the term it was added in
+    waiters: HashMap<u64, (u64, Waiter)>
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64 - 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log[self.log.len() - 1].term
+    }
+
+    fn reset_deadline(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.deadline = Instant::now() + Duration::from_millis(ELECTION_TIMEOUT + self.rng % ELECTION_TIMEOUT);
+    }
+
+    fn save_meta(&self) -> io::Result<()> {
+        match self.disk {
+            Some(ref disk) => disk.save_meta(self.term, &self.voted_for),
+            None => Ok(())
+        }
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> io::Result<()> {
+        if let Some(ref mut disk) = self.disk {
+            disk.append(&entries)?;
+        }
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        if let Some(ref mut disk) = self.disk {
+            disk.truncate(index)?;
+        }
+        self.log.truncate(index as usize);
+        Ok(())
+    }
+
+    /// Moves on to `term` as a follower if it is newer than the current one.
+    fn observe(&mut self, term: u64) -> io::Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.save_meta()?;
+        }
+        Ok(())
+    }
+}
+
+/// A member of a Raft cluster.
+pub struct Raft {
+    id: String,
+    peers: Vec<String>,
+    databases: Arc<Databases>,
+    // sent along with every request to the other members
+    password: Option<String>,
+    state: Mutex<State>,
+    // notified whenever the state changes in a way others may wait for
+    changed: Condvar
+}
+
+impl Raft {
+    /// Creates the member `id` of a cluster of `members`, restoring its state
+    /// from `dir` if given. Nothing happens until it is started.
+    pub fn new(id: &str, members: &[String], dir: Option<&str>, databases: Arc<Databases>,
+               password: Option<String>) -> io::Result<Raft> {
+        if !members.iter().any(|member| member == id) {
+            return Err(invalid(&format!("{} is not one of the cluster members", id)));
+        }
+
+        let (disk, term, voted_for, entries) = match dir {
+            Some(dir) => {
+                let (disk, term, voted_for, entries) = Disk::open(dir)?;
+                info!("Restored Raft term {} and {} log entries from {}", term, entries.len(), dir);
+                (Some(disk), term, voted_for, entries)
+            },
+            None => {
+                warn!("No Raft directory configured, cluster state will not survive a restart");
+                (None, 0, None, Vec::new())
+            }
+        };
+
+        let mut hasher = DefaultHasher::new();
+        (id, Instant::now()).hash(&mut hasher);
+
+        let mut log = vec![Entry { term: 0, record: None }];
+        log.extend(entries);
+
+        let mut state = State {
+            term,
+            voted_for,
+            log,
+            disk,
+            commit: 0,
+            applied: 0,
+            role: Role::Follower,
+            leader: None,
+            deadline: Instant::now(),
+            rng: hasher.finish() | 1,
+            votes: 0,
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            round: 0,
+            acked: HashMap::new(),
+            waiters: HashMap::new()
+        };
+        state.reset_deadline();
+
+        Ok(Raft {
+            id: id.to_string(),
+            peers: members.iter().filter(|member| *member != id).cloned().collect(),
+            databases,
+            password,
+            state: Mutex::new(state),
+            changed: Condvar::new()
+        })
+    }
+
+    /// Starts taking part in elections and applying committed entries.
+    pub fn start(self: &Arc<Self>) {
+        let raft = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(10));
+            let state = raft.state.lock().unwrap();
+            if state.role != Role::Leader && Instant::now() >= state.deadline {
+                if let Err(e) = raft.campaign(state) {
+                    error!("Failed to start election: {}", e);
+                }
+            }
+        });
+
+        let raft = Arc::clone(self);
+        thread::spawn(move || raft.apply_committed());
+    }
+
+    fn majority(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn not_leader(state: &State) -> Box<dyn std::error::Error> {
+        let message = match state.leader {
+            Some(ref leader) => format!("Not the leader, the leader is {}", leader),
+            None => "Not the leader, no leader elected yet".to_string()
+        };
+        Box::new(error::StorageError(message))
+    }
+
+    /// Appends `record` to the log and returns the result of applying it once
+    /// it is committed. Fails if this member is not the leader.
+    pub fn propose(&self, record: Record) -> Response {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(Raft::not_leader(&state));
+            }
+
+            let term = state.term;
+            state.append(vec![Entry { term, record: Some(record) }])?;
+
+            let (sender, receiver) = mpsc::channel();
+            let index = state.last_index();
+            state.waiters.insert(index, (term, sender));
+            self.advance_commit(&mut state);
+            self.changed.notify_all();
+            receiver
+        };
+
+        match receiver.recv_timeout(CLIENT_TIMEOUT) {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(Box::new(error::StorageError(e))),
+            Err(_) => Err(Box::new(error::StorageError("Timed out waiting for the write to be committed".to_string())))
+        }
+    }
+
+    /// Returns once reads reflect every write acknowledged before. Fails if
+    /// this member is not, or is no longer, the leader.
+    pub fn read_barrier(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Err(Raft::not_leader(&state));
+        }
+        let term = state.term;
+
+        // only once an entry of its own term is committed does a new leader
+        // know everything committed before it
+        state = self.wait(state, |state| state.log[state.commit as usize].term == term)?;
+
+        let index = state.commit;
+        state.round += 1;
+        let round = state.round;
+        self.changed.notify_all();
+
+        let majority = self.majority();
+        let peers = &self.peers;
+        self.wait(state, |state| {
+            let confirmed = 1 + peers.iter().filter(|peer| state.acked.get(*peer).is_some_and(|r| *r >= round)).count();
+            confirmed >= majority && state.applied >= index
+        }).map(|_| ())
+    }
+
+    /// Waits until `done` holds, failing if leadership is lost or the client
+    /// timeout passes first.
+    fn wait<'a, F>(&self, state: MutexGuard<'a, State>, done: F) -> Result<MutexGuard<'a, State>>
+        where
+            F: Fn(&State) -> bool
+    {
+        let term = state.term;
+        let (state, _) = self.changed.wait_timeout_while(state, CLIENT_TIMEOUT, |state| {
+            state.role == Role::Leader && state.term == term && !done(state)
+        }).unwrap();
+
+        if state.role != Role::Leader || state.term != term {
+            return Err(Raft::not_leader(&state));
+        }
+        if !done(&state) {
+            return Err(Box::new(error::StorageError("Timed out confirming leadership".to_string())));
+        }
+        Ok(state)
+    }
+
+    /// Answers a request from another member.
+    pub fn handle(&self, stream: &mut TcpStream, mut request: Vec<u8>) -> Response {
+        stream.read_to_end(&mut request)?;
+        let request = String::from_utf8(request)?;
+
+        let mut fields = Fields::new(&request);
+        fields.next()?;
+        let answer = match fields.next()? {
+            VOTE => {
+                let term = fields.number()?;
+                let candidate = fields.next()?;
+                let (last_index, last_term) = (fields.number()?, fields.number()?);
+                self.vote(term, candidate, last_index, last_term)?
+            },
+
+            APPEND => {
+                let term = fields.number()?;
+                let leader = fields.next()?;
+                let (prev_index, prev_term, commit) = (fields.number()?, fields.number()?, fields.number()?);
+                let entries = (0..fields.number()?)
+                    .map(|_| Entry::read_fields(&mut fields))
+                    .collect::<Result<Vec<Entry>>>()?;
+                self.append(term, leader, prev_index, prev_term, commit, entries)?
+            },
+
+            _ => return Err(Box::new(error::ParseError))
+        };
+
+        Ok(Some(answer.iter().map(|n| n.to_string()).collect::<Vec<String>>().join(&SEP.to_string())))
+    }
+
+    fn vote(&self, term: u64, candidate: &str, last_index: u64, last_term: u64) -> Result<Vec<u64>> {
+        let mut state = self.state.lock().unwrap();
+        state.observe(term)?;
+
+        let up_to_date = last_term > state.last_term()
+            || last_term == state.last_term() && last_index >= state.last_index();
+        let granted = term == state.term
+            && state.voted_for.as_ref().is_none_or(|vote| vote == candidate)
+            && up_to_date;
+
+        if granted {
+            state.voted_for = Some(candidate.to_string());
+            state.save_meta()?;
+            state.reset_deadline();
+        }
+        Ok(vec![state.term, granted as u64])
+    }
+
+    fn append(&self, term: u64, leader: &str, prev_index: u64, prev_term: u64, commit: u64,
+              entries: Vec<Entry>) -> Result<Vec<u64>> {
+
+        let mut state = self.state.lock().unwrap();
+        state.observe(term)?;
+        if term < state.term {
+            return Ok(vec![state.term, 0, state.last_index()]);
+        }
+
+        state.role = Role::Follower;
+        state.leader = Some(leader.to_string());
+        state.reset_deadline();
+
+        if prev_index > state.last_index() {
+            return Ok(vec![state.term, 0, state.last_index()]);
+        }
+        if state.log[prev_index as usize].term != prev_term {
+            return Ok(vec![state.term, 0, prev_index - 1]);
+        }
+
+        let last = prev_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for (index, entry) in (prev_index + 1..).zip(entries) {
+            if index <= state.last_index() {
+                if state.log[index as usize].term == entry.term {
+                    continue;
+                }
+                state.truncate(index)?;
+            }
+            new.push(entry);
+        }
+        state.append(new)?;
+
+        if commit > state.commit {
+            state.commit = commit.min(last);
+            self.changed.notify_all();
+        }
+        Ok(vec![state.term, 1, last])
+    }
+
+    /// Starts an election for the next term.
+    fn campaign(self: &Arc<Self>, mut state: MutexGuard<State>) -> io::Result<()> {
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(self.id.clone());
+        state.leader = None;
+        state.votes = 1;
+        state.save_meta()?;
+        state.reset_deadline();
+        debug!("Starting election for term {}", state.term);
+
+        if state.votes >= self.majority() {
+            return self.lead(&mut state);
+        }
+
+        let request = [RAFT.to_string(), VOTE.to_string(), state.term.to_string(), self.id.clone(),
+            state.last_index().to_string(), state.last_term().to_string()].join(&SEP.to_string());
+        let request = crate::authenticated(self.password.as_deref(), &request);
+        let term = state.term;
+        drop(state);
+
+        for peer in self.peers.clone() {
+            let raft = Arc::clone(self);
+            let request = request.clone();
+            thread::spawn(move || {
+                let answer = match call(&peer, &request) {
+                    Ok(answer) => answer,
+                    Err(e) => return debug!("Vote request to {} failed: {}", peer, e)
+                };
+
+                let mut state = raft.state.lock().unwrap();
+                if let Err(e) = state.observe(answer[0]) {
+                    return error!("Failed to save Raft state: {}", e);
+                }
+
+                if state.role == Role::Candidate && state.term == term && answer[1] == 1 {
+                    state.votes += 1;
+                    if state.votes >= raft.majority() {
+                        if let Err(e) = raft.lead(&mut state) {
+                            error!("Failed to take the lead: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Becomes the leader of the current term.
+    fn lead(self: &Arc<Self>, state: &mut State) -> io::Result<()> {
+        info!("Elected leader for term {}", state.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+
+        let term = state.term;
+        state.append(vec![Entry { term, record: None }])?;
+
+        for peer in &self.peers {
+            state.next.insert(peer.clone(), state.last_index());
+            state.matched.insert(peer.clone(), 0);
+            state.acked.insert(peer.clone(), 0);
+
+            let raft = Arc::clone(self);
+            let peer = peer.clone();
+            thread::spawn(move || raft.replicate(&peer, term));
+        }
+
+        self.advance_commit(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Sends entries and heartbeats to `peer` for as long as this member leads
+    /// in `term`.
+    fn replicate(&self, peer: &str, term: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.role != Role::Leader || state.term != term {
+                return;
+            }
+
+            let next = state.next[peer];
+            let prev_index = next - 1;
+            let entries: Vec<Entry> = state.log[next as usize..].iter().take(BATCH).cloned().collect();
+            let round = state.round;
+
+            let mut fields = vec![RAFT.to_string(), APPEND.to_string(), term.to_string(), self.id.clone(),
+                prev_index.to_string(), state.log[prev_index as usize].term.to_string(),
+                state.commit.to_string(), entries.len().to_string()];
+            for entry in &entries {
+                entry.write_fields(&mut fields);
+            }
+            drop(state);
+
+            let answer = call(peer, &crate::authenticated(self.password.as_deref(), &fields.join(&SEP.to_string())));
+            state = self.state.lock().unwrap();
+
+            match answer {
+                Ok(answer) => {
+                    if let Err(e) = state.observe(answer[0]) {
+                        error!("Failed to save Raft state: {}", e);
+                    }
+                    if state.role != Role::Leader || state.term != term {
+                        return;
+                    }
+
+                    let acked = state.acked[peer].max(round);
+                    state.acked.insert(peer.to_string(), acked);
+
+                    if answer[1] == 1 {
+                        let matched = state.matched[peer].max(answer[2]);
+                        state.matched.insert(peer.to_string(), matched);
+                        state.next.insert(peer.to_string(), matched + 1);
+                        self.advance_commit(&mut state);
+                    } else {
+                        let next = (next - 1).min(answer[2] + 1).max(1);
+                        state.next.insert(peer.to_string(), next);
+                    }
+                    self.changed.notify_all();
+                },
+
+                Err(e) => {
+                    debug!("Append to {} failed: {}", peer, e);
+                    state = self.changed.wait_timeout(state, HEARTBEAT).unwrap().0;
+                    continue;
+                }
+            }
+
+            // wait for new entries or reads to confirm, or until the next heartbeat
+            state = self.changed.wait_timeout_while(state, HEARTBEAT, |state| {
+                state.role == Role::Leader && state.term == term
+                    && state.next[peer] > state.last_index()
+                    && state.acked[peer] >= state.round
+            }).unwrap().0;
+        }
+    }
+
+    /// Commits every entry of the current term stored by a majority.
+    fn advance_commit(&self, state: &mut State) {
+        let mut matched: Vec<u64> = self.peers.iter().map(|peer| state.matched[peer]).collect();
+        matched.push(state.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let index = matched[self.majority() - 1];
+        if index > state.commit && state.log[index as usize].term == state.term {
+            state.commit = index;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Applies committed entries in order and answers the clients waiting for
+    /// them.
+    fn apply_committed(&self) {
+        loop {
+            let entries: Vec<(u64, Entry)> = {
+                let state = self.state.lock().unwrap();
+                let state = self.changed.wait_while(state, |state| state.applied >= state.commit).unwrap();
+                (state.applied + 1..=state.commit).map(|index| (index, state.log[index as usize].clone())).collect()
+            };
+
+            for (index, entry) in entries {
+                let result = match entry.record {
+                    Some(record) => apply(&self.databases, record).map_err(|e| e.to_string()),
+                    None => Ok(None)
+                };
+
+                let mut state = self.state.lock().unwrap();
+                state.applied = index;
+                if let Some((term, waiter)) = state.waiters.remove(&index) {
+                    let _ = waiter.send(if term == entry.term {
+                        result
+                    } else {
+                        Err("Leadership changed before the write was committed".to_string())
+                    });
+                }
+                self.changed.notify_all();
+            }
+        }
+    }
+}
+
+fn apply(databases: &Databases, record: Record) -> Result<Option<String>> {
+    let db = databases.get(record.db())?;
+    match record {
+        Record::Insert { key, value, .. } => db.insert(key, value),
+        // fails like outside of cluster mode, without affecting the other members
+        Record::Remove { key, .. } => match db.remove(&key)? {
+            Some(old) => Ok(Some(old)),
+            None => Err(Box::new(error::StorageError(format!("Key not found: {}", key))))
+        },
+        Record::Clear { .. } => {
+            db.clear()?;
+            Ok(None)
+        }
+    }
+}
+
+/// Sends `request` to the member at `peer` and returns the numbers it answers with.
+fn call(peer: &str, request: &str) -> Result<Vec<u64>> {
+    let address = peer.to_socket_addrs()?.next().ok_or_else(|| invalid("Unresolvable address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, RPC_TIMEOUT)?;
+    stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+    stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+
+    let mut fields = Fields::new(&answer);
+    if fields.next()? != "OK" {
+        return Err(Box::new(error::StorageError(answer.clone())));
+    }
+    fields.0.map(|field| Ok(field.parse().map_err(|_| error::ParseError)?)).collect()
+}
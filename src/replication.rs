@@ -0,0 +1,343 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Every record an instance applies advances its replication offset by one and
+// is kept in a bounded backlog. A follower connects to its leader and sends
+// SYNC with the replication id and offset it has reached. If that id is the
+// leader's, or the one it had before being promoted, and the records after the
+// offset are still in the backlog, the leader answers CONTINUE with its id and
+// sends just those records. Otherwise it answers FULL with its id, its offset
+// and a number of entries, followed by an INSERT record for each of them.
+// Either way, every record the leader applies from then on follows. Answers
+// are frames and records are encoded as in the append-only log.
+//
+// Replication is asynchronous: the leader acknowledges writes without waiting
+// for its followers. Locks and queues are not replicated.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{process, thread};
+use crate::aof::Record;
+use crate::codec::{frame, invalid, read_frame};
+use crate::db::Databases;
+use crate::{error, Result, SEP};
+
+/// The request a follower opens its replication stream with.
+pub const SYNC: &str = "SYNC";
+const CONTINUE: &str = "CONTINUE";
+const FULL: &str = "FULL";
+
+struct State {
+    id: String,
+    offset: u64,
+    // id and final offset of the history before the last promotion
+    previous: Option<(String, u64)>,
+    // frames of the most recent records, the last one at `offset`
+    backlog: VecDeque<Vec<u8>>,
+    backlog_bytes: usize,
+    followers: Vec<Sender<Vec<u8>>>
+}
+
+impl State {
+    /// Returns the records following `offset` in the history `id`, or `None`
+    /// if they are not in the backlog.
+    fn since(&self, id: &str, offset: u64) -> Option<Vec<Vec<u8>>> {
+        let known = id == self.id
+            || self.previous.as_ref().is_some_and(|(previous, end)| previous == id && offset <= *end);
+        let start = self.offset - self.backlog.len() as u64;
+
+        if !known || offset < start || offset > self.offset {
+            return None;
+        }
+        Some(self.backlog.iter().skip((offset - start) as usize).cloned().collect())
+    }
+}
+
+/// The leader this instance follows, if any.
+#[derive(Default)]
+struct Following {
+    leader: Option<String>,
+    // changed with the leader, ending the sync thread of the previous one
+    generation: u64,
+    // the connection to the leader, shut down to interrupt the sync thread
+    stream: Option<TcpStream>
+}
+
+/// The replication state of an instance, both as a leader and as a follower.
+pub struct Replication {
+    backlog_size: usize,
+    // sent to the leader along with SYNC
+    password: Option<String>,
+    state: Mutex<State>,
+    following: Mutex<Following>
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize, password: Option<String>) -> Replication {
+        Replication {
+            backlog_size,
+            password,
+            state: Mutex::new(State {
+                id: new_id(),
+                offset: 0,
+                previous: None,
+                backlog: VecDeque::new(),
+                backlog_bytes: 0,
+                followers: Vec::new()
+            }),
+            following: Mutex::new(Following::default())
+        }
+    }
+
+    /// Runs `apply` and sends `record` to all followers while holding them, so
+    /// followers receive records in the order they were applied. Nothing is sent
+    /// if `apply` fails.
+    pub fn publish<F, R>(&self, record: &Record, apply: F) -> io::Result<R>
+        where
+            F: FnOnce() -> io::Result<R>
+    {
+        let frame = record.encode()?;
+        let mut state = self.state.lock().unwrap();
+        let result = apply()?;
+
+        state.offset += 1;
+        // followers whose connection is gone have dropped their receiver
+        state.followers.retain(|follower| follower.send(frame.clone()).is_ok());
+
+        state.backlog_bytes += frame.len();
+        state.backlog.push_back(frame);
+        while state.backlog_bytes > self.backlog_size {
+            match state.backlog.pop_front() {
+                Some(old) => state.backlog_bytes -= old.len(),
+                None => break
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Runs `f` while no record is applied, so it sees all databases at the
+    /// same offset, which is returned along with its result.
+    pub fn frozen<F, R>(&self, f: F) -> (R, u64)
+        where
+            F: FnOnce() -> R
+    {
+        let state = self.state.lock().unwrap();
+        (f(), state.offset)
+    }
+
+    /// Returns the current replication id and offset.
+    pub fn position(&self) -> (String, u64) {
+        let state = self.state.lock().unwrap();
+        (state.id.clone(), state.offset)
+    }
+
+    /// Answers the SYNC `request` of the follower at `stream` and streams all
+    /// records applied from now on to it, on a separate thread.
+    pub fn serve(&self, stream: TcpStream, request: &str, databases: Arc<Databases>) {
+        let split: Vec<&str> = request.split(SEP).collect();
+        let (id, offset) = match split[..] {
+            [_, id, offset] => match offset.parse::<u64>() {
+                Ok(offset) => (id, offset),
+                Err(_) => return refuse(stream, &error::ParseError)
+            },
+            _ => return refuse(stream, &error::ParseError)
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let mut state = self.state.lock().unwrap();
+
+        // decided while holding the followers, so no record is missed
+        let answer = match state.since(id, offset) {
+            Some(frames) => {
+                for frame in frames {
+                    let _ = sender.send(frame);
+                }
+                vec![CONTINUE.to_string(), state.id.clone()]
+            },
+            None => vec![FULL.to_string(), state.id.clone(), state.offset.to_string()]
+        };
+        state.followers.push(sender);
+        drop(state);
+
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            info!("Follower {} connected ({})", peer, answer[0]);
+            if let Err(e) = stream_to(stream, answer, &databases, receiver) {
+                info!("Follower {} disconnected: {}", peer, e);
+            }
+        });
+    }
+
+    /// Returns the address of the leader, if this instance is a follower.
+    pub fn leader(&self) -> Option<String> {
+        self.following.lock().unwrap().leader.clone()
+    }
+
+    /// Starts following the leader at `leader` on a separate thread, replacing
+    /// the leader followed before. Whenever the connection is lost, it is
+    /// reestablished.
+    pub fn follow(self: &Arc<Self>, leader: String, databases: Arc<Databases>) {
+        let generation = self.set_leader(Some(leader.clone()));
+        let replication = Arc::clone(self);
+
+        thread::spawn(move || {
+            while replication.is_current(generation) {
+                if let Err(e) = replication.sync(&leader, &databases, generation) {
+                    if replication.is_current(generation) {
+                        error!("Replication from {} interrupted: {}", leader, e);
+                    }
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+
+    /// Stops following and starts a new replication history. Followers of the
+    /// previous leader can continue from this instance as long as they have not
+    /// gotten further than it.
+    pub fn promote(&self) -> Result<()> {
+        if self.leader().is_none() {
+            return Err(Box::new(error::StorageError("Not a follower".to_string())));
+        }
+        self.set_leader(None);
+
+        let mut state = self.state.lock().unwrap();
+        let previous = std::mem::replace(&mut state.id, new_id());
+        state.previous = Some((previous, state.offset));
+        info!("Promoted to leader at offset {}", state.offset);
+        Ok(())
+    }
+
+    fn set_leader(&self, leader: Option<String>) -> u64 {
+        let mut following = self.following.lock().unwrap();
+        following.leader = leader;
+        following.generation += 1;
+        if let Some(stream) = following.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        following.generation
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.following.lock().unwrap().generation == generation
+    }
+
+    fn sync(&self, leader: &str, databases: &Databases, generation: u64) -> Result<()> {
+        let mut stream = TcpStream::connect(leader)?;
+        {
+            let mut following = self.following.lock().unwrap();
+            if following.generation != generation {
+                return Ok(());
+            }
+            following.stream = Some(stream.try_clone()?);
+        }
+
+        let (id, offset) = self.position();
+        let request = format!("{}{}{}{}{}", SYNC, SEP, id, SEP, offset);
+        stream.write_all(crate::authenticated(self.password.as_deref(), &request).as_bytes())?;
+        stream.flush()?;
+
+        let answer = String::from_utf8(read_frame(&mut stream)?)?;
+        match answer.split(SEP).collect::<Vec<&str>>()[..] {
+            [CONTINUE, id] => {
+                self.state.lock().unwrap().id = id.to_string();
+                info!("Continuing replication from {} at offset {}", leader, offset);
+            },
+
+            [FULL, id, offset, count] => {
+                for (_, db) in databases.all() {
+                    db.clear()?;
+                }
+                for _ in 0..count.parse::<u64>()? {
+                    apply(databases, Record::decode(&read_frame(&mut stream)?)?)?;
+                }
+
+                let offset = offset.parse()?;
+                self.reset(id, offset);
+                info!("Resynchronized {} entries from {} at offset {}", count, leader, offset);
+            },
+
+            _ => return Err(Box::new(invalid(&format!("Unexpected answer from {}: {}", leader, answer))))
+        }
+
+        loop {
+            apply(databases, Record::decode(&read_frame(&mut stream)?)?)?;
+        }
+    }
+
+    /// Adopts the history `id` of a leader at `offset`. Followers of this
+    /// instance are disconnected, since the records they have seen no longer
+    /// match its offsets.
+    fn reset(&self, id: &str, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.id = id.to_string();
+        state.offset = offset;
+        state.previous = None;
+        state.backlog.clear();
+        state.backlog_bytes = 0;
+        state.followers.clear();
+    }
+}
+
+fn stream_to(mut stream: TcpStream, answer: Vec<String>, databases: &Databases,
+             receiver: Receiver<Vec<u8>>) -> io::Result<()> {
+
+    if answer[0] == FULL {
+        let mut records = Vec::new();
+        for (name, db) in databases.all() {
+            for (key, value) in db.entries() {
+                records.push(Record::Insert { db: name.clone(), key, value }.encode()?);
+            }
+        }
+
+        let answer = format!("{}{}{}", answer.join(&SEP.to_string()), SEP, records.len());
+        stream.write_all(&frame(answer.as_bytes()))?;
+        for record in records {
+            stream.write_all(&record)?;
+        }
+    } else {
+        stream.write_all(&frame(answer.join(&SEP.to_string()).as_bytes()))?;
+    }
+
+    for frame in receiver {
+        stream.write_all(&frame)?;
+    }
+    Ok(())
+}
+
+fn refuse(mut stream: TcpStream, e: &dyn std::error::Error) {
+    let answer = format!("ERR{}{}", SEP, e);
+    if let Err(e) = stream.write_all(&frame(answer.as_bytes())) {
+        error!("{}", e);
+    }
+}
+
+/// Applies a record received from the leader like any other write, so it is
+/// logged and passed on to this instance's own followers.
+fn apply(databases: &Databases, record: Record) -> Result<()> {
+    let db = databases.get(record.db())?;
+    match record {
+        Record::Insert { key, value, .. } => { db.insert(key, value)?; },
+        Record::Remove { key, .. } => { db.remove(&key)?; },
+        Record::Clear { .. } => db.clear()?
+    }
+    Ok(())
+}
+
+fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = DefaultHasher::new();
+    (SystemTime::now(), process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)).hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
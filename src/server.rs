@@ -0,0 +1,228 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use crate::args::{Config, Recovery};
+use crate::acl::Acl;
+use crate::audit::Audit;
+use crate::aof::{self, Aof};
+use crate::backup::Backup;
+use crate::db::Databases;
+use crate::limits::Limits;
+use crate::memory::Memory;
+use crate::raft::Raft;
+use crate::replication::Replication;
+use crate::slots::Slots;
+use crate::{error, snapshot, Result};
+
+/// State shared by all connections of a running instance.
+pub struct Server {
+    pub databases: Arc<Databases>,
+    // required with every request, if set
+    pub password: Option<String>,
+    pub acl: Option<Acl>,
+    pub limits: Limits,
+    pub audit: Option<Audit>,
+    snapshot: Option<String>,
+    aof: Option<Arc<Aof>>,
+    pub replication: Arc<Replication>,
+    pub raft: Option<Arc<Raft>>,
+    pub slots: Option<Slots>,
+    recovery: Recovery,
+    saving: AtomicBool,
+    read_only: AtomicBool,
+    // the only directory backups are written to
+    backup_dir: Option<PathBuf>,
+    // the running or last finished online backup
+    backup: Mutex<Option<Arc<Backup>>>
+}
+
+impl Server {
+    pub fn new(config: &Config) -> Result<Server> {
+        let aof = match config.aof {
+            Some(ref path) => {
+                aof::recover(path, config.recovery)?;
+                Some(Aof::open(path, config.fsync, config.aof_rewrite_ratio, config.aof_rewrite_min_size)?)
+            },
+            None => None
+        };
+
+        let memory = config.max_memory.map(|limit| Arc::new(Memory::new(limit, config.eviction)));
+        let replication = Arc::new(Replication::new(config.repl_backlog_size, config.requirepass.clone()));
+
+        let databases = Databases::new(config.history, config.engine.clone(), aof.clone(), memory,
+            Arc::clone(&replication));
+
+        let raft = if config.cluster.is_empty() {
+            None
+        } else {
+            let raft = Raft::new(&config.iface, &config.cluster, config.raft_dir.as_deref(), Arc::clone(&databases),
+                config.requirepass.clone())?;
+            Some(Arc::new(raft))
+        };
+
+        let slots = if config.slots.is_empty() {
+            None
+        } else {
+            Some(Slots::new(&config.iface, &config.slots, config.requirepass.clone())?)
+        };
+
+        Ok(Server {
+            databases,
+            password: config.requirepass.clone(),
+            acl: config.acl_file.as_deref().map(Acl::open).transpose()?,
+            limits: Limits::new(config),
+            audit: config.audit_log.as_deref().map(|path| Audit::open(path, config.audit_values)).transpose()?,
+            snapshot: config.snapshot.clone(),
+            aof,
+            replication,
+            raft,
+            slots,
+            recovery: config.recovery,
+            saving: AtomicBool::new(false),
+            read_only: AtomicBool::new(config.read_only),
+            backup_dir: config.backup_dir.as_ref().map(PathBuf::from),
+            backup: Mutex::new(None)
+        })
+    }
+
+    /// Loads the configured snapshot, if one exists, and replays the append-only
+    /// log on top of it.
+    pub fn load(&self) -> Result<()> {
+        let n = self.databases.reopen()?;
+        if n > 0 {
+            info!("Opened {} databases persisted by the storage engine", n);
+        }
+
+        if let Some(ref path) = self.snapshot {
+            if std::path::Path::new(path).exists() {
+                let n = snapshot::load(&self.databases, path, self.recovery)?;
+                info!("Loaded {} entries from snapshot {}", n, path);
+            }
+        }
+
+        if let Some(ref aof) = self.aof {
+            let databases = &self.databases;
+            let n = Aof::replay(aof.path(), |record| databases.get(record.db())?.replay(record))?;
+            info!("Replayed {} records from append-only log {}", n, aof.path());
+        }
+
+        Ok(())
+    }
+
+    /// Writes a snapshot to the configured path and returns its checksum.
+    pub fn save(&self) -> Result<u32> {
+        let path = self.snapshot_path()?;
+        self.begin_save()?;
+        let (copy, _) = snapshot::copy(&self.databases, &self.replication);
+        let result = snapshot::write(&path, copy, |_| ());
+        self.saving.store(false, Ordering::SeqCst);
+        Ok(result?)
+    }
+
+    /// Copies all databases and writes them to a snapshot on a separate thread.
+    pub fn bgsave(self: Arc<Self>) -> Result<()> {
+        let path = self.snapshot_path()?;
+        self.begin_save()?;
+        let (copy, _) = snapshot::copy(&self.databases, &self.replication);
+
+        thread::spawn(move || {
+            match snapshot::write(&path, copy, |_| ()) {
+                Ok(checksum) => info!("Saved snapshot to {} (checksum {:08x})", path, checksum),
+                Err(e) => error!("Failed to save snapshot to {}: {}", path, e)
+            }
+            self.saving.store(false, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+
+    /// Starts an online backup to `name` inside the backup directory, unless one
+    /// is already running.
+    pub fn backup(&self, name: &str) -> Result<()> {
+        let dir = self.backup_dir.as_ref().ok_or_else(|| {
+            error::StorageError("No backup directory is configured".to_string())
+        })?;
+
+        // clients may only name files inside the backup directory
+        let name = Path::new(name);
+        if name.as_os_str().is_empty() || !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(Box::new(error::StorageError(format!("Invalid backup path: {}", name.display()))));
+        }
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut backup = self.backup.lock().unwrap();
+        if backup.as_ref().is_some_and(|backup| backup.is_running()) {
+            return Err(Box::new(error::StorageError("A backup is already in progress".to_string())));
+        }
+        *backup = Some(Backup::start(&self.databases, &self.replication, &path.to_string_lossy()));
+        Ok(())
+    }
+
+    /// Returns the status of the running or last finished backup.
+    pub fn backup_status(&self) -> Result<String> {
+        match *self.backup.lock().unwrap() {
+            Some(ref backup) => Ok(backup.status()),
+            None => Err(Box::new(error::StorageError("No backup has been started".to_string())))
+        }
+    }
+
+    /// Makes the server reject or accept writes from clients.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+        info!("Read-only mode {}", if read_only { "enabled" } else { "disabled" });
+    }
+
+    /// Fails if the server rejects writes from clients. Writes replicated from
+    /// a leader or committed in cluster mode are still applied.
+    pub fn writable(&self) -> Result<()> {
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(Box::new(error::ReadOnlyError));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the append-only log should be rewritten.
+    pub fn needs_rewrite(&self) -> bool {
+        self.aof.as_ref().is_some_and(|aof| aof.needs_rewrite())
+    }
+
+    /// Rewrites the append-only log on a separate thread.
+    pub fn bgrewrite(self: Arc<Self>) -> Result<()> {
+        let aof = self.aof.clone().ok_or_else(|| {
+            Box::new(error::StorageError("No append-only log configured".to_string())) as Box<dyn std::error::Error>
+        })?;
+        aof.begin_rewrite()?;
+
+        thread::spawn(move || {
+            match aof.rewrite(&self.databases) {
+                Ok(size) => info!("Rewrote append-only log {} ({} bytes)", aof.path(), size),
+                Err(e) => error!("Failed to rewrite append-only log {}: {}", aof.path(), e)
+            }
+        });
+
+        Ok(())
+    }
+
+    fn snapshot_path(&self) -> Result<String> {
+        self.snapshot.clone().ok_or_else(|| {
+            Box::new(error::StorageError("No snapshot path configured".to_string())) as Box<dyn std::error::Error>
+        })
+    }
+
+    fn begin_save(&self) -> Result<()> {
+        if self.saving.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(Box::new(error::StorageError("A save is already in progress".to_string())));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Sharding partitions the keys into SLOTS slots, each served by one node. A
+// node answers commands on keys of slots it does not serve with
+// `MOVED slot node`. Slots can be moved between nodes while they serve traffic:
+//
+// 1. `SETSLOT slot IMPORTING source` on the target node,
+// 2. `SETSLOT slot MIGRATING target` on the source node,
+// 3. `MIGRATE slot` on the source node, which moves the slot's entries,
+// 4. `SETSLOT slot NODE target` on every node.
+//
+// While a slot is migrating, the source serves the keys it still holds and
+// answers `ASK slot target` for the others. The target only serves the slot
+// to requests prefixed with `ASKING`. Locks, queues and history stay behind.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Condvar, Mutex, RwLock};
+use crate::db::Databases;
+use crate::{error, Result, SEP};
+
+/// The number of slots keys are partitioned into.
+pub const SLOTS: u16 = 16384;
+
+/// Returns the slot of `key`. If the key contains a non-empty `{tag}`, only the
+/// tag is hashed, so that related keys can be kept on the same node.
+pub fn slot(key: &str) -> u16 {
+    let tagged = key.find('{')
+        .and_then(|open| key[open + 1..].find('}').map(|close| &key[open + 1..open + 1 + close]))
+        .filter(|tag| !tag.is_empty());
+
+    (crc32fast::hash(tagged.unwrap_or(key).as_bytes()) % u32::from(SLOTS)) as u16
+}
+
+// marks a key in `KeyLocks` as being moved
+const MOVING: usize = usize::MAX;
+
+/// Locks on single keys of migrating slots, shared by the commands on a key and
+/// taken exclusively while its entry is moved.
+#[derive(Default)]
+struct KeyLocks {
+    // the number of commands running on each key, or MOVING
+    keys: Mutex<HashMap<String, usize>>,
+    released: Condvar
+}
+
+impl KeyLocks {
+    fn share(&self, key: &str) -> KeyGuard<'_> {
+        let mut keys = self.keys.lock().unwrap();
+        while keys.get(key) == Some(&MOVING) {
+            keys = self.released.wait(keys).unwrap();
+        }
+        *keys.entry(key.to_string()).or_insert(0) += 1;
+        KeyGuard { locks: self, key: key.to_string() }
+    }
+
+    fn take(&self, key: &str) -> KeyGuard<'_> {
+        let mut keys = self.keys.lock().unwrap();
+        while keys.contains_key(key) {
+            keys = self.released.wait(keys).unwrap();
+        }
+        keys.insert(key.to_string(), MOVING);
+        KeyGuard { locks: self, key: key.to_string() }
+    }
+
+    fn release(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get_mut(key) {
+            Some(count) if *count != MOVING && *count > 1 => *count -= 1,
+            _ => { keys.remove(key); }
+        }
+        self.released.notify_all();
+    }
+}
+
+/// Releases a key of a migrating slot when dropped.
+pub struct KeyGuard<'a> {
+    locks: &'a KeyLocks,
+    key: String
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.release(&self.key);
+    }
+}
+
+struct State {
+    owners: Vec<Option<String>>,
+    // slots moving away from this node, with their target
+    migrating: HashMap<u16, String>,
+    // slots moving to this node, with their source
+    importing: HashMap<u16, String>
+}
+
+/// The assignment of slots to nodes, as seen by this node.
+pub struct Slots {
+    id: String,
+    // sent to other nodes along with the entries moved to them
+    password: Option<String>,
+    state: RwLock<State>,
+    // taken for a key while its entry is moved, and shared by commands on keys
+    // of migrating slots, so that an entry is never changed while being moved
+    moving: KeyLocks
+}
+
+impl Slots {
+    /// Creates the slot map of node `id`, assigning each range of slots to its
+    /// node. Slots outside of the ranges are unassigned.
+    pub fn new(id: &str, ranges: &[(u16, u16, String)], password: Option<String>) -> Result<Slots> {
+        let mut owners = vec![None; SLOTS as usize];
+        for (start, end, node) in ranges {
+            if start > end || *end >= SLOTS {
+                return Err(Box::new(error::StorageError(format!("Invalid slot range {}-{}", start, end))));
+            }
+            for owner in &mut owners[*start as usize..=*end as usize] {
+                *owner = Some(node.clone());
+            }
+        }
+
+        Ok(Slots {
+            id: id.to_string(),
+            password,
+            state: RwLock::new(State { owners, migrating: HashMap::new(), importing: HashMap::new() }),
+            moving: KeyLocks::default()
+        })
+    }
+
+    /// Checks whether a command on `key` is served by this node, and redirects
+    /// the client otherwise. `exists` tells whether the key is stored here and
+    /// `asking` whether the request was prefixed with `ASKING`. The returned
+    /// guard must be held while the command runs.
+    pub fn route<F>(&self, key: &str, asking: bool, exists: F) -> Result<Option<KeyGuard<'_>>>
+        where
+            F: FnOnce() -> bool
+    {
+        let slot = slot(key);
+        let state = self.state.read().unwrap();
+        let owner = state.owners[slot as usize].as_ref().ok_or_else(|| {
+            error::StorageError(format!("Slot {} is not assigned", slot))
+        })?;
+
+        if *owner != self.id {
+            if asking && state.importing.contains_key(&slot) {
+                return Ok(None);
+            }
+            return Err(Box::new(error::RedirectError { ask: false, slot, node: owner.clone() }));
+        }
+
+        let target = match state.migrating.get(&slot) {
+            Some(target) => target.clone(),
+            None => return Ok(None)
+        };
+        drop(state);
+
+        let guard = self.moving.share(key);
+        if exists() {
+            Ok(Some(guard))
+        } else {
+            Err(Box::new(error::RedirectError { ask: true, slot, node: target }))
+        }
+    }
+
+    /// Fails with a redirect unless `key` belongs to a slot assigned to this
+    /// node that is not being migrated, for commands on many keys.
+    pub fn owns(&self, key: &str) -> Result<()> {
+        let slot = slot(key);
+        let state = self.state.read().unwrap();
+        match state.owners[slot as usize] {
+            Some(ref owner) if *owner != self.id => {
+                Err(Box::new(error::RedirectError { ask: false, slot, node: owner.clone() }))
+            },
+            None => Err(Box::new(error::StorageError(format!("Slot {} is not assigned", slot)))),
+            Some(_) if state.migrating.contains_key(&slot) => {
+                Err(Box::new(error::StorageError(format!("Slot {} is being migrated", slot))))
+            },
+            Some(_) => Ok(())
+        }
+    }
+
+    /// Returns whether entries of the slot of `key` are served by this node,
+    /// because it is assigned to or being imported by it.
+    pub fn serves(&self, key: &str) -> bool {
+        let slot = slot(key);
+        let state = self.state.read().unwrap();
+        state.owners[slot as usize].as_deref() == Some(&self.id) || state.importing.contains_key(&slot)
+    }
+
+    /// Returns whether the slot of `key` is being imported from another node.
+    pub fn imports(&self, key: &str) -> bool {
+        self.state.read().unwrap().importing.contains_key(&slot(key))
+    }
+
+    /// Returns the assigned ranges of slots with their node, marking migrating
+    /// and importing slots.
+    pub fn describe(&self) -> Vec<String> {
+        let state = self.state.read().unwrap();
+        let mut fields = Vec::new();
+        let mut start = 0;
+
+        for slot in 1..=SLOTS as usize {
+            if slot < SLOTS as usize && state.owners[slot] == state.owners[start] {
+                continue;
+            }
+            if let Some(ref node) = state.owners[start] {
+                fields.push(format!("{}-{} {}", start, slot - 1, node));
+            }
+            start = slot;
+        }
+
+        let mut moves: Vec<String> = state.migrating.iter()
+            .map(|(slot, target)| format!("{} MIGRATING {}", slot, target))
+            .chain(state.importing.iter().map(|(slot, source)| format!("{} IMPORTING {}", slot, source)))
+            .collect();
+        moves.sort();
+        fields.extend(moves);
+        fields
+    }
+
+    /// Assigns `slot` to `node`, ending any migration of it.
+    pub fn assign(&self, slot: u16, node: &str) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        *state.owners.get_mut(slot as usize).ok_or_else(|| invalid_slot(slot))? = Some(node.to_string());
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        Ok(())
+    }
+
+    /// Marks `slot`, served by this node, as moving to `target`.
+    pub fn migrating(&self, slot: u16, target: &str) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if state.owners.get(slot as usize).ok_or_else(|| invalid_slot(slot))?.as_deref() != Some(&self.id) {
+            return Err(Box::new(error::StorageError(format!("Slot {} is not served by this node", slot))));
+        }
+        state.migrating.insert(slot, target.to_string());
+        Ok(())
+    }
+
+    /// Marks `slot` as moving to this node from `source`.
+    pub fn importing(&self, slot: u16, source: &str) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if state.owners.get(slot as usize).ok_or_else(|| invalid_slot(slot))?.as_deref() == Some(&self.id) {
+            return Err(Box::new(error::StorageError(format!("Slot {} is already served by this node", slot))));
+        }
+        state.importing.insert(slot, source.to_string());
+        Ok(())
+    }
+
+    /// Moves all entries of the migrating `slot` to its target and returns
+    /// their number.
+    pub fn migrate(&self, slot: u16, databases: &Databases) -> Result<usize> {
+        let target = self.state.read().unwrap().migrating.get(&slot).cloned().ok_or_else(|| {
+            error::StorageError(format!("Slot {} is not migrating", slot))
+        })?;
+
+        let mut moved = 0;
+        for (name, db) in databases.all() {
+            for (key, _) in db.entries() {
+                if self::slot(&key) != slot {
+                    continue;
+                }
+
+                let _guard = self.moving.take(&key);
+                let value = match db.get(&key) {
+                    Some(value) => value,
+                    None => continue
+                };
+
+                let request = ["ASKING", "SELECT", &name, "INSERT", &key, &value].join(&SEP.to_string());
+                send(&target, &crate::authenticated(self.password.as_deref(), &request))?;
+                db.remove(&key)?;
+                moved += 1;
+            }
+        }
+
+        info!("Moved {} entries of slot {} to {}", moved, slot, target);
+        Ok(moved)
+    }
+}
+
+fn invalid_slot(slot: u16) -> Box<dyn std::error::Error> {
+    Box::new(error::StorageError(format!("Invalid slot {}", slot)))
+}
+
+/// Sends `request` to the node at `node` and fails unless it succeeds.
+fn send(node: &str, request: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(node)?;
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    if !response.starts_with("OK") {
+        return Err(Box::new(error::StorageError(format!("{} refused entry: {}", node, response.replace(SEP, " ")))));
+    }
+    Ok(())
+}
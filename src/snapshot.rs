@@ -0,0 +1,184 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use crate::args::Recovery;
+use crate::codec::{frame, invalid, write_str, Reader};
+use crate::db::Databases;
+use crate::replication::Replication;
+
+// File layout: MAGIC and VERSION, followed by frames (see `codec`). A DATABASE
+// frame holds a database name and is followed by an ENTRY frame for every
+// key-value pair in that database. An END frame holds the total number of
+// entries. The file ends with the CRC32 of everything before it.
+const MAGIC: &[u8] = b"YOCTO";
+const VERSION: u8 = 2;
+const DATABASE: u8 = 1;
+const ENTRY: u8 = 2;
+const END: u8 = 0;
+
+/// The entries of each database, along with its name.
+pub type Contents = Vec<(String, Vec<(String, String)>)>;
+
+/// Copies the entries of all databases at a single replication offset, which
+/// is returned along with them. Writers are only held up while copying.
+pub fn copy(databases: &Databases, replication: &Replication) -> (Contents, u64) {
+    replication.frozen(|| {
+        databases.all().into_iter()
+            .map(|(name, db)| (name, db.entries()))
+            .collect()
+    })
+}
+
+/// Writes the entries of each database to a snapshot at `path` and returns
+/// its checksum, calling `progress` with the number of entries written so far
+/// after each entry. The snapshot is written to a temporary file which then
+/// atomically replaces `path`.
+pub fn write<I, P>(path: &str, databases: I, mut progress: P) -> io::Result<u32>
+    where
+        I: IntoIterator<Item = (String, Vec<(String, String)>)>,
+        P: FnMut(u64)
+{
+    let tmp = format!("{}.tmp", path);
+    let mut writer = ChecksumWriter::new(BufWriter::new(File::create(&tmp)?));
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+
+    let mut total = 0u64;
+    for (name, entries) in databases {
+        let mut payload = vec![DATABASE];
+        write_str(&mut payload, &name)?;
+        writer.write_all(&frame(&payload))?;
+
+        for (key, value) in entries {
+            let mut payload = vec![ENTRY];
+            write_str(&mut payload, &key)?;
+            write_str(&mut payload, &value)?;
+            writer.write_all(&frame(&payload))?;
+            total += 1;
+            progress(total);
+        }
+    }
+
+    let mut payload = vec![END];
+    payload.extend_from_slice(&total.to_le_bytes());
+    writer.write_all(&frame(&payload))?;
+
+    let (mut inner, checksum) = writer.finish();
+    inner.write_all(&checksum.to_le_bytes())?;
+    inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(checksum)
+}
+
+/// Loads the snapshot at `path` into `databases` and returns the number of
+/// entries read. Every record is verified before anything is loaded. On
+/// corruption, strict recovery fails without loading anything, while degraded
+/// recovery loads all records before the corrupt one.
+pub fn load(databases: &Databases, path: &str, recovery: Recovery) -> io::Result<usize> {
+    let data = fs::read(path)?;
+    if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid(&format!("{} is not a yocto snapshot", path)));
+    }
+    if data[MAGIC.len()] != VERSION {
+        return Err(invalid(&format!("Unsupported version {} of snapshot {}", data[MAGIC.len()], path)));
+    }
+
+    let mut reader = Reader::new(&data, MAGIC.len() + 1);
+    let mut loaded: Contents = Vec::new();
+    let mut total = 0;
+
+    let corruption = loop {
+        let offset = reader.position();
+        let payload = match reader.frame() {
+            Ok(payload) => payload,
+            Err(e) => break Some((offset, format!("{:?} error", e)))
+        };
+
+        let mut record = Reader::new(payload, 0);
+        let parsed = record.u8().and_then(|tag| match tag {
+            DATABASE => {
+                loaded.push((record.string()?, Vec::new()));
+                Ok(false)
+            },
+            ENTRY => {
+                let entry = (record.string()?, record.string()?);
+                let db = loaded.last_mut().ok_or_else(|| invalid("entry outside of a database"))?;
+                db.1.push(entry);
+                total += 1;
+                Ok(false)
+            },
+            END if record.u64()? == total as u64 => Ok(true),
+            END => Err(invalid("entry count mismatch")),
+            _ => Err(invalid("unknown record type"))
+        });
+
+        match parsed {
+            Ok(true) => break None,
+            Ok(false) => (),
+            Err(e) => break Some((offset, e.to_string()))
+        }
+    };
+
+    let corruption = corruption.or_else(|| {
+        let body = &data[..reader.position()];
+        let mut trailer = Reader::new(&data, reader.position());
+        match trailer.u32() {
+            Ok(checksum) if checksum == crc32fast::hash(body) && trailer.remaining() == 0 => None,
+            _ => Some((reader.position(), "file checksum mismatch".to_string()))
+        }
+    });
+
+    if let Some((offset, reason)) = corruption {
+        error!("Snapshot {} is corrupt at offset {} after {} valid entries: {}", path, offset, total, reason);
+
+        if recovery == Recovery::Strict {
+            return Err(invalid(&format!(
+                "Corrupt snapshot {} at offset {}; start with --recovery degraded to load the valid part", path, offset)));
+        }
+        error!("Starting degraded: loading {} entries from the valid part of the snapshot", total);
+    }
+
+    for (name, entries) in loaded {
+        databases.get(&name)?.restore(entries)?;
+    }
+
+    Ok(total)
+}
+
+/// Computes the CRC32 of everything written through it.
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            hasher: crc32fast::Hasher::new()
+        }
+    }
+
+    /// Returns the inner writer and the checksum of all data written so far.
+    fn finish(self) -> (W, u32) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
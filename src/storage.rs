@@ -0,0 +1,318 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+//! Storage engines a database can be backed by.
+//!
+//! Every engine implements the [`Storage`](trait.Storage.html) trait. The built-in
+//! engines are selected through [`Engine`](enum.Engine.html); to embed yocto with
+//! an engine of your own, pass an `Engine::Custom` factory in the run config.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::{fs, io};
+use chashmap::CHashMap;
+
+mod mapped;
+
+pub use self::mapped::MappedStorage;
+
+/// A thread-safe string map the database commands operate on.
+pub trait Storage: Send + Sync {
+    /// Returns the value stored at `key`.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Stores `value` at `key` and returns the previous value. Only persistent
+    /// engines fail, if they cannot write the entry.
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>>;
+
+    /// Removes `key` and returns its value.
+    fn remove(&self, key: &str) -> io::Result<Option<String>>;
+
+    /// Removes all entries and returns the keys that were removed.
+    fn clear(&self) -> io::Result<Vec<String>>;
+
+    /// Returns the number of stored entries.
+    fn len(&self) -> usize;
+
+    /// Returns a copy of all entries, in no particular order.
+    fn entries(&self) -> Vec<(String, String)>;
+
+    /// Returns whether the storage holds no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns up to `limit` entries with `start <= key < end` in key order, or
+    /// `None` if the engine does not keep its keys ordered.
+    fn range(&self, _start: &str, _end: &str, _limit: usize) -> Option<Vec<(String, String)>> {
+        None
+    }
+
+    /// Returns up to `limit` entries whose key starts with `prefix` in key order,
+    /// or `None` if the engine does not keep its keys ordered.
+    fn prefix(&self, _prefix: &str, _limit: usize) -> Option<Vec<(String, String)>> {
+        None
+    }
+}
+
+/// Creates a fresh storage for every database.
+pub type Factory = Arc<dyn Fn() -> Box<dyn Storage> + Send + Sync>;
+
+/// The storage engine new databases are created with.
+#[derive(Clone, Default)]
+pub enum Engine {
+    /// A concurrent hash map locking per bucket.
+    #[default]
+    Hashed,
+
+    /// A `BTreeMap` behind a read-write lock, supporting range and prefix queries.
+    Ordered,
+
+    /// A fixed number of `HashMap`s, each behind its own read-write lock.
+    Sharded(usize),
+
+    /// A memory-mapped file per database in the given directory, whose entries
+    /// persist across restarts.
+    Mapped(PathBuf),
+
+    /// A user-supplied engine.
+    Custom(Factory)
+}
+
+impl Engine {
+    /// Creates the storage of the database `name`. Only persistent engines
+    /// make use of the name, and only they fail if their files cannot be opened.
+    pub fn create(&self, name: &str) -> io::Result<Box<dyn Storage>> {
+        match self {
+            Engine::Mapped(dir) => {
+                let path = dir.join(file_name(name));
+                let storage = MappedStorage::open(&path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Failed to open {}: {}", path.display(), e))
+                })?;
+                Ok(Box::new(storage))
+            },
+            engine => Ok(engine.transient())
+        }
+    }
+
+    /// Creates a storage in memory that supports the same queries as those of
+    /// this engine, but persists nothing.
+    pub fn transient(&self) -> Box<dyn Storage> {
+        match self {
+            Engine::Hashed | Engine::Mapped(_) => Box::new(HashStorage::new()),
+            Engine::Ordered => Box::new(OrderedStorage::new()),
+            Engine::Sharded(shards) => Box::new(ShardedStorage::new(*shards)),
+            Engine::Custom(factory) => factory()
+        }
+    }
+
+    /// Returns the names of the databases whose entries persist from a previous
+    /// run, creating the directory of a persistent engine if needed.
+    pub fn persisted(&self) -> io::Result<Vec<String>> {
+        let dir = match self {
+            Engine::Mapped(dir) => dir,
+            _ => return Ok(Vec::new())
+        };
+
+        fs::create_dir_all(dir)?;
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            if let Some(name) = entry?.file_name().to_str().and_then(database_name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+// database names may contain any character, so files are named by their hex
+fn file_name(name: &str) -> String {
+    let hex: String = name.bytes().map(|b| format!("{:02x}", b)).collect();
+    format!("{}.{}", hex, MAPPED_EXTENSION)
+}
+
+fn database_name(file_name: &str) -> Option<String> {
+    let hex = file_name.strip_suffix(MAPPED_EXTENSION)?.strip_suffix('.')?;
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "hashed" => Ok(Engine::Hashed),
+            "ordered" => Ok(Engine::Ordered),
+            "sharded" => Ok(Engine::Sharded(DEFAULT_SHARDS)),
+            "mapped" => Ok(Engine::Mapped(PathBuf::from(DEFAULT_MAPPED_DIR))),
+            _ => Err(format!("Unknown storage engine: {}", s))
+        }
+    }
+}
+
+const DEFAULT_SHARDS: usize = 16;
+const DEFAULT_MAPPED_DIR: &str = "data";
+const MAPPED_EXTENSION: &str = "map";
+
+/// Storage backed by a `CHashMap`.
+#[derive(Default)]
+pub struct HashStorage {
+    map: CHashMap<String, String>
+}
+
+impl HashStorage {
+    pub fn new() -> HashStorage {
+        HashStorage::default()
+    }
+}
+
+impl Storage for HashStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        self.map.get(key).map(|v| v.to_string())
+    }
+
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>> {
+        Ok(self.map.insert(key, value))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.map.remove(key))
+    }
+
+    fn clear(&self) -> io::Result<Vec<String>> {
+        Ok(self.map.clear().into_iter().map(|(k, _)| k).collect())
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        // cloning only holds each bucket while it is copied
+        self.map.clone().into_iter().collect()
+    }
+}
+
+/// Storage backed by a `BTreeMap`, which keeps keys in order.
+#[derive(Default)]
+pub struct OrderedStorage {
+    map: RwLock<BTreeMap<String, String>>
+}
+
+impl OrderedStorage {
+    pub fn new() -> OrderedStorage {
+        OrderedStorage::default()
+    }
+}
+
+impl Storage for OrderedStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        self.map.read().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>> {
+        Ok(self.map.write().unwrap().insert(key, value))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.map.write().unwrap().remove(key))
+    }
+
+    fn clear(&self) -> io::Result<Vec<String>> {
+        let old = std::mem::take(&mut *self.map.write().unwrap());
+        Ok(old.into_keys().collect())
+    }
+
+    fn len(&self) -> usize {
+        self.map.read().unwrap().len()
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.map.read().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    fn range(&self, start: &str, end: &str, limit: usize) -> Option<Vec<(String, String)>> {
+        if end <= start {
+            return Some(Vec::new());
+        }
+
+        Some(self.map.read().unwrap()
+            .range::<str, _>((Bound::Included(start), Bound::Excluded(end)))
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn prefix(&self, prefix: &str, limit: usize) -> Option<Vec<(String, String)>> {
+        Some(self.map.read().unwrap()
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+}
+
+/// Storage spreading keys over several independently locked `HashMap`s.
+pub struct ShardedStorage {
+    shards: Vec<RwLock<HashMap<String, String>>>
+}
+
+impl ShardedStorage {
+    pub fn new(shards: usize) -> ShardedStorage {
+        assert!(shards > 0);
+
+        ShardedStorage {
+            shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect()
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, String>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+impl Storage for ShardedStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>> {
+        Ok(self.shard(&key).write().unwrap().insert(key, value))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        Ok(self.shard(key).write().unwrap().remove(key))
+    }
+
+    fn clear(&self) -> io::Result<Vec<String>> {
+        Ok(self.shards.iter()
+            .flat_map(|shard| std::mem::take(&mut *shard.write().unwrap()).into_keys())
+            .collect())
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.shards.iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
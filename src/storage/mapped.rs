@@ -0,0 +1,364 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// File layout: a header of HEADER bytes, a hash table of `buckets` slots and a
+// heap of records. Header fields are little-endian u64s at the offsets below.
+// Each slot holds the file offset of a record, EMPTY or REMOVED, and keys are
+// placed by linear probing. A record consists of the key length and the value
+// length as little-endian u32s, followed by the key and the value.
+//
+// Records are only ever appended; replacing or removing an entry leaves its
+// old record behind as garbage. Once the table is three quarters full, with
+// removed slots counted, or most of the heap is garbage, the live entries are
+// copied into a new file which then atomically replaces the old one.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use memmap2::MmapMut;
+use super::Storage;
+
+const MAGIC: &[u8] = b"YOCTOMAP";
+const VERSION: u64 = 1;
+const HEADER: u64 = 64;
+
+const VERSION_AT: usize = 8;
+const BUCKETS_AT: usize = 16;
+const LEN_AT: usize = 24;
+// slots holding a record or REMOVED
+const USED_AT: usize = 32;
+// offset the next record is appended at
+const END_AT: usize = 40;
+// bytes of records no slot refers to
+const GARBAGE_AT: usize = 48;
+
+const EMPTY: u64 = 0;
+const REMOVED: u64 = 1;
+
+const MIN_BUCKETS: u64 = 1024;
+const MIN_HEAP: u64 = 1 << 16;
+const MIN_GARBAGE: u64 = 1 << 20;
+
+/// Storage keeping its entries in a memory-mapped file, so they persist across
+/// restarts without being loaded and the operating system can page out those
+/// not in use. A write that fails leaves the file as it was.
+pub struct MappedStorage {
+    path: PathBuf,
+    file: RwLock<Mapped>
+}
+
+impl MappedStorage {
+    /// Opens the storage at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedStorage> {
+        let path = path.as_ref().to_path_buf();
+        let file = if path.exists() {
+            Mapped::open(&path)?
+        } else {
+            Mapped::create(&path, MIN_BUCKETS, MIN_HEAP)?
+        };
+
+        Ok(MappedStorage { path, file: RwLock::new(file) })
+    }
+}
+
+impl Storage for MappedStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        let file = self.file.read().unwrap();
+        file.find(key.as_bytes()).1.map(|record| file.value(record))
+    }
+
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>> {
+        let mut file = self.file.write().unwrap();
+
+        // the table is rebuilt before the insert, so it never fills up even if
+        // rebuilding fails. Removed slots count as used, so it only grows if the
+        // live entries alone take up more than half of it, which leaves at least
+        // a quarter of it free after rebuilding at the same size
+        if (file.field(USED_AT) + 1) * 4 > file.buckets() * 3 {
+            let buckets = if (file.field(LEN_AT) + 1) * 2 > file.buckets() {
+                file.buckets() * 2
+            } else {
+                file.buckets()
+            };
+            file.rebuild(&self.path, buckets)?;
+        }
+        file.insert(key.as_bytes(), value.as_bytes())
+    }
+
+    fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        let mut file = self.file.write().unwrap();
+        let old = file.remove(key.as_bytes());
+
+        // the entry is removed either way, compacting is retried on the next
+        // removal if it fails
+        let garbage = file.field(GARBAGE_AT);
+        if garbage > MIN_GARBAGE && garbage * 2 > file.field(END_AT) - file.heap() {
+            let buckets = file.buckets();
+            if let Err(e) = file.rebuild(&self.path, buckets) {
+                error!("Failed to compact {}: {}", self.path.display(), e);
+            }
+        }
+        Ok(old)
+    }
+
+    fn clear(&self) -> io::Result<Vec<String>> {
+        let mut file = self.file.write().unwrap();
+        let keys = file.records().map(|record| file.key(record)).collect();
+        *file = Mapped::replace(&self.path, MIN_BUCKETS, MIN_HEAP, |_| Ok(()))?;
+        Ok(keys)
+    }
+
+    fn len(&self) -> usize {
+        self.file.read().unwrap().field(LEN_AT) as usize
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        let file = self.file.read().unwrap();
+        file.records().map(|record| (file.key(record), file.value(record))).collect()
+    }
+}
+
+/// An open storage file.
+struct Mapped {
+    file: File,
+    map: MmapMut
+}
+
+impl Mapped {
+    fn create(path: &Path, buckets: u64, heap: u64) -> io::Result<Mapped> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(HEADER + buckets * 8 + heap.max(MIN_HEAP))?;
+
+        let mut mapped = Mapped::map(file)?;
+        mapped.map[..MAGIC.len()].copy_from_slice(MAGIC);
+        mapped.set(VERSION_AT, VERSION);
+        mapped.set(BUCKETS_AT, buckets);
+        mapped.set(END_AT, HEADER + buckets * 8);
+        Ok(mapped)
+    }
+
+    fn open(path: &Path) -> io::Result<Mapped> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() < HEADER {
+            return Err(invalid(path));
+        }
+
+        let mapped = Mapped::map(file)?;
+        if &mapped.map[..MAGIC.len()] != MAGIC || mapped.field(VERSION_AT) != VERSION || !mapped.is_valid() {
+            return Err(invalid(path));
+        }
+        Ok(mapped)
+    }
+
+    /// Returns whether the table and every record it refers to lie within the
+    /// file, and the counts in the header match the table.
+    fn is_valid(&self) -> bool {
+        let (buckets, end) = (self.buckets(), self.field(END_AT));
+        let heap = match buckets.checked_mul(8).and_then(|table| table.checked_add(HEADER)) {
+            Some(heap) if buckets.is_power_of_two() && heap <= end && end <= self.map.len() as u64 => heap,
+            _ => return false
+        };
+
+        let (mut len, mut used) = (0, 0);
+        for bucket in 0..buckets {
+            match self.slot(bucket) {
+                EMPTY => continue,
+                REMOVED => (),
+                record => {
+                    if record < heap || record > end - 8 {
+                        return false;
+                    }
+                    let (key, value) = self.lengths(record);
+                    if record + 8 + (key + value) as u64 > end {
+                        return false;
+                    }
+                    len += 1;
+                }
+            }
+            used += 1;
+        }
+
+        // lookups only terminate if a slot is empty
+        len == self.field(LEN_AT) && used == self.field(USED_AT) && used < buckets
+    }
+
+    /// Creates a new file filled by `fill` next to `path`, which it then
+    /// replaces.
+    fn replace<F>(path: &Path, buckets: u64, heap: u64, fill: F) -> io::Result<Mapped>
+        where
+            F: FnOnce(&mut Mapped) -> io::Result<()>
+    {
+        let tmp = path.with_extension("tmp");
+        let mut mapped = Mapped::create(&tmp, buckets, heap)?;
+        fill(&mut mapped)?;
+        mapped.map.flush()?;
+        fs::rename(&tmp, path)?;
+        Ok(mapped)
+    }
+
+    fn map(file: File) -> io::Result<Mapped> {
+        // the file is only ever modified through this mapping
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Mapped { file, map })
+    }
+
+    fn field(&self, at: usize) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&self.map[at..at + 8]);
+        u64::from_le_bytes(buf)
+    }
+
+    fn set(&mut self, at: usize, value: u64) {
+        self.map[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn buckets(&self) -> u64 {
+        self.field(BUCKETS_AT)
+    }
+
+    /// Returns the offset of the heap.
+    fn heap(&self) -> u64 {
+        HEADER + self.buckets() * 8
+    }
+
+    fn slot(&self, bucket: u64) -> u64 {
+        self.field((HEADER + bucket * 8) as usize)
+    }
+
+    fn set_slot(&mut self, bucket: u64, value: u64) {
+        self.set((HEADER + bucket * 8) as usize, value);
+    }
+
+    /// Returns the key and value lengths of the record at `record`.
+    fn lengths(&self, record: u64) -> (usize, usize) {
+        let at = record as usize;
+        let mut buf = [0; 4];
+        buf.copy_from_slice(&self.map[at..at + 4]);
+        let key = u32::from_le_bytes(buf) as usize;
+        buf.copy_from_slice(&self.map[at + 4..at + 8]);
+        (key, u32::from_le_bytes(buf) as usize)
+    }
+
+    fn key_bytes(&self, record: u64) -> &[u8] {
+        let (key, _) = self.lengths(record);
+        &self.map[record as usize + 8..record as usize + 8 + key]
+    }
+
+    fn key(&self, record: u64) -> String {
+        String::from_utf8_lossy(self.key_bytes(record)).into_owned()
+    }
+
+    fn value(&self, record: u64) -> String {
+        let (key, value) = self.lengths(record);
+        let at = record as usize + 8 + key;
+        String::from_utf8_lossy(&self.map[at..at + value]).into_owned()
+    }
+
+    fn size(&self, record: u64) -> u64 {
+        let (key, value) = self.lengths(record);
+        (8 + key + value) as u64
+    }
+
+    /// Returns the offsets of all live records.
+    fn records(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.buckets()).map(move |bucket| self.slot(bucket)).filter(|&slot| slot > REMOVED)
+    }
+
+    /// Returns the bucket `key` is stored in, or should be stored in if it is
+    /// missing, and the offset of its record if it exists.
+    fn find(&self, key: &[u8]) -> (u64, Option<u64>) {
+        let mask = self.buckets() - 1;
+        let mut bucket = hash(key) & mask;
+        let mut free = None;
+
+        loop {
+            match self.slot(bucket) {
+                EMPTY => return (free.unwrap_or(bucket), None),
+                REMOVED => { free.get_or_insert(bucket); },
+                record if self.key_bytes(record) == key => return (bucket, Some(record)),
+                _ => ()
+            }
+            bucket = (bucket + 1) & mask;
+        }
+    }
+
+    /// Appends a record and returns its offset, growing the file if needed.
+    fn append(&mut self, key: &[u8], value: &[u8]) -> io::Result<u64> {
+        let record = self.field(END_AT);
+        let end = record + 8 + (key.len() + value.len()) as u64;
+
+        if end > self.map.len() as u64 {
+            self.map.flush_async()?;
+            self.file.set_len(end.max(self.map.len() as u64 * 2))?;
+            self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        }
+
+        let at = record as usize;
+        self.map[at..at + 4].copy_from_slice(&(key.len() as u32).to_le_bytes());
+        self.map[at + 4..at + 8].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        self.map[at + 8..at + 8 + key.len()].copy_from_slice(key);
+        self.map[at + 8 + key.len()..end as usize].copy_from_slice(value);
+        self.set(END_AT, end);
+        Ok(record)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> io::Result<Option<String>> {
+        let (bucket, old) = self.find(key);
+        let record = self.append(key, value)?;
+
+        if self.slot(bucket) == EMPTY {
+            self.set(USED_AT, self.field(USED_AT) + 1);
+        }
+        self.set_slot(bucket, record);
+
+        match old {
+            Some(old) => {
+                self.set(GARBAGE_AT, self.field(GARBAGE_AT) + self.size(old));
+                Ok(Some(self.value(old)))
+            },
+            None => {
+                self.set(LEN_AT, self.field(LEN_AT) + 1);
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<String> {
+        let (bucket, old) = self.find(key);
+        let old = old?;
+
+        self.set_slot(bucket, REMOVED);
+        self.set(LEN_AT, self.field(LEN_AT) - 1);
+        self.set(GARBAGE_AT, self.field(GARBAGE_AT) + self.size(old));
+        Some(self.value(old))
+    }
+
+    /// Replaces the file at `path` with one holding only the live entries, in
+    /// a table of `buckets` slots.
+    fn rebuild(&mut self, path: &Path, buckets: u64) -> io::Result<()> {
+        let heap = self.records().map(|record| self.size(record)).sum();
+        let rebuilt = Mapped::replace(path, buckets, heap, |rebuilt| {
+            for record in self.records() {
+                let (key, value) = self.lengths(record);
+                let at = record as usize + 8;
+                rebuilt.insert(&self.map[at..at + key], &self.map[at + key..at + key + value])?;
+            }
+            Ok(())
+        })?;
+
+        *self = rebuilt;
+        Ok(())
+    }
+}
+
+fn invalid(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a storage file", path.display()))
+}
+
+/// FNV-1a, which unlike the standard hasher is stable across releases.
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3))
+}
@@ -16,7 +16,7 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<FnBox + Send + 'static>;
+type Job = Box<dyn FnBox + Send + 'static>;
 
 enum Message {
     Job(Job),
@@ -24,7 +24,6 @@ enum Message {
 }
 
 struct Worker {
-    id: usize,
     thread: Option<thread::JoinHandle<()>>
 }
 
@@ -49,7 +48,6 @@ impl Worker {
         });
 
         Worker {
-            id,
             thread: Some(thread)
         }
     }
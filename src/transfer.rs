@@ -0,0 +1,133 @@
+//
+// (c) 2019 Alexander Becker
+// Released under the MIT license.
+//
+
+// Moves the entries of a database in or out of a server in bulk. `DUMP format`
+// returns all entries of the selected database, `RESTORE format data` inserts
+// the entries in `data`. Since the data of a RESTORE may span several reads,
+// clients shut down their side of the connection after sending it.
+//
+// In JSON Lines, each entry is an object `{"key": ..., "value": ...}` on a line
+// of its own. In CSV, the first record is the header `key,value` and each
+// following record holds an entry.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use crate::args::{Format, Transfer};
+use crate::{error, SEP};
+
+/// Encodes `entries` in `format`, ordered by key.
+pub(crate) fn encode(mut entries: Vec<(String, String)>, format: Format) -> crate::Result<String> {
+    entries.sort();
+
+    match format {
+        Format::JsonLines => Ok(entries.into_iter()
+            .map(|(key, value)| serde_json::json!({ "key": key, "value": value }).to_string() + "\n")
+            .collect()),
+
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            writer.write_record(["key", "value"])?;
+            for (key, value) in entries {
+                writer.write_record([key, value])?;
+            }
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+/// Decodes the entries in `data`, failing on the first malformed one.
+pub(crate) fn decode(data: &str, format: Format) -> crate::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+
+    match format {
+        Format::JsonLines => {
+            for (i, line) in data.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                let object: serde_json::Value = serde_json::from_str(line).map_err(|e| {
+                    invalid(format!("Line {}: {}", i + 1, e))
+                })?;
+                match (object.get("key").and_then(|k| k.as_str()), object.get("value").and_then(|v| v.as_str())) {
+                    (Some(key), Some(value)) => entries.push(checked(i as u64 + 1, key, value)?),
+                    _ => return Err(invalid(format!("Line {}: expected string key and value", i + 1)))
+                }
+            }
+        },
+
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(data.as_bytes());
+            for record in reader.records() {
+                let record = record?;
+                let line = record.position().map_or(0, |p| p.line());
+                match (record.get(0), record.get(1), record.len()) {
+                    (Some(key), Some(value), 2) => entries.push(checked(line, key, value)?),
+                    _ => return Err(invalid(format!("Line {}: expected key and value", line)))
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// keys and values containing the separator could not be addressed afterwards
+fn checked(line: u64, key: &str, value: &str) -> crate::Result<(String, String)> {
+    if key.contains(SEP) || value.contains(SEP) {
+        return Err(invalid(format!("Line {}: keys and values must not contain \\u001f", line)));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn invalid(message: String) -> Box<dyn Error> {
+    Box::new(error::StorageError(message))
+}
+
+/// Writes all entries of a database to the file of `transfer`, or to the
+/// standard output if it has none.
+pub fn export(transfer: &Transfer) -> Result<(), Box<dyn Error>> {
+    let data = send(transfer, &format!("DUMP{}{}", SEP, transfer.format))?;
+
+    match transfer.file {
+        Some(ref path) => File::create(path)?.write_all(data.as_bytes())?,
+        None => io::stdout().write_all(data.as_bytes())?
+    }
+    Ok(())
+}
+
+/// Inserts the entries in the file of `transfer`, or in the standard input if
+/// it has none, into a database and returns their number.
+pub fn import(transfer: &Transfer) -> Result<usize, Box<dyn Error>> {
+    let mut data = String::new();
+    match transfer.file {
+        Some(ref path) => File::open(path)?.read_to_string(&mut data)?,
+        None => io::stdin().read_to_string(&mut data)?
+    };
+
+    // fail before sending anything if the data is malformed
+    decode(&data, transfer.format)?;
+
+    let count = send(transfer, &format!("RESTORE{}{}{}{}", SEP, transfer.format, SEP, data))?;
+    Ok(count.parse()?)
+}
+
+/// Sends `command` on the database of `transfer` and returns the response value.
+fn send(transfer: &Transfer, command: &str) -> Result<String, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(&transfer.iface)?;
+    let request = format!("SELECT{}{}{}{}", SEP, transfer.db, SEP, command);
+    stream.write_all(crate::authenticated(transfer.password.as_deref(), &request).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (status, value) = match response.find(SEP) {
+        Some(i) => (&response[..i], &response[i + SEP.len_utf8()..]),
+        None => (response.as_str(), "")
+    };
+    match status {
+        "OK" => Ok(value.to_string()),
+        _ => Err(invalid(value.to_string()))
+    }
+}
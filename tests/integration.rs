@@ -3,16 +3,24 @@
 // Released under the MIT license.
 //
 
-use yocto::args::Config;
-use std::io::prelude::*;
+use yocto::args::{self, Action, Cidr, Config, Eviction, Format, Fsync, Recovery, Transfer};
+use yocto::storage::{Engine, HashStorage, MappedStorage, Storage};
+use std::io::{self, prelude::*};
 use log::LogLevelFilter;
 use std::thread;
-use std::time::Duration;
-use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::net::{Shutdown, TcpStream};
 use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const SEP: char = '\u{1f}';
 
+// every test server binds its own port, so tests can run in parallel, except
+// for those started by `bootstrap`
+static PORT: AtomicUsize = AtomicUsize::new(7003);
+static FIXED_PORT: Mutex<()> = Mutex::new(());
+
 #[test]
 fn invalid_command() {
     bootstrap(1);
@@ -111,37 +119,1517 @@ fn test() {
     assert_ok(res, None);
 }
 
+#[test]
+fn history_disabled() {
+    let iface = start(1);
+    let res = send_to(&iface, format!("HISTORY{}key", SEP));
+    assert_error(res);
+}
+
+#[test]
+fn history() {
+    let iface = bootstrap_with(3, Config { history: 5, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}key{}new_value", SEP, SEP));
+    let res = send_to(&iface, format!("HISTORY{}key", SEP));
+    let split: Vec<&str> = res.split(SEP).collect();
+    assert_eq!(split[0], "OK");
+    assert_eq!(split.len(), 5);
+    assert_eq!(split[2], "value");
+    assert_eq!(split[4], "new_value");
+}
+
+#[test]
+fn history_retention() {
+    let iface = bootstrap_with(4, Config { history: 2, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}a", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}key{}b", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}key{}c", SEP, SEP));
+    let res = send_to(&iface, format!("HISTORY{}key", SEP));
+    let split: Vec<&str> = res.split(SEP).collect();
+    assert_eq!(split.len(), 5);
+    assert_eq!(split[2], "b");
+    assert_eq!(split[4], "c");
+}
+
+#[test]
+fn get_at() {
+    let iface = bootstrap_with(5, Config { history: 5, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let before = timestamp();
+    let _ = send_to(&iface, format!("INSERT{}key{}new_value", SEP, SEP));
+    let _ = send_to(&iface, format!("REMOVE{}key", SEP));
+    let res = send_to(&iface, format!("GET{}key{}AT{}{}", SEP, SEP, SEP, before));
+    assert_ok(res, Some("value".to_string()));
+    let res = send_to(&iface, format!("GET{}key{}AT{}{}", SEP, SEP, SEP, timestamp()));
+    assert_ok(res, None);
+}
+
+#[test]
+fn lock_exclusive() {
+    let iface = start(2);
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}a", SEP, SEP, SEP));
+    assert!(token(res) > 0);
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}b", SEP, SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn lock_fencing_token() {
+    let iface = start(3);
+    let first = token(send_to(&iface, format!("LOCK{}job{}10000{}a", SEP, SEP, SEP)));
+    let res = send_to(&iface, format!("UNLOCK{}job{}a", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}b", SEP, SEP, SEP));
+    assert_eq!(token(res), first + 1);
+
+    // tokens keep increasing on a server started later, such as after a restart
+    let iface = start(1);
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}c", SEP, SEP, SEP));
+    assert!(token(res) > first + 1);
+}
+
+#[test]
+fn lock_expires() {
+    let iface = start(3);
+    let first = token(send_to(&iface, format!("LOCK{}job{}50{}a", SEP, SEP, SEP)));
+    thread::sleep(Duration::from_millis(100));
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}b", SEP, SEP, SEP));
+    assert_eq!(token(res), first + 1);
+    let res = send_to(&iface, format!("UNLOCK{}job{}a", SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn lock_refresh() {
+    let iface = start(3);
+    let first = token(send_to(&iface, format!("LOCK{}job{}10000{}a", SEP, SEP, SEP)));
+    let res = send_to(&iface, format!("REFRESH{}job{}a{}10000", SEP, SEP, SEP));
+    assert_eq!(token(res), first);
+    let res = send_to(&iface, format!("REFRESH{}job{}b{}10000", SEP, SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn lock_huge_ttl() {
+    let iface = start(4);
+    let first = token(send_to(&iface, format!("LOCK{}job{}{}{}a", SEP, SEP, u64::MAX, SEP)));
+    let res = send_to(&iface, format!("LOCK{}job{}10000{}b", SEP, SEP, SEP));
+    assert_error(res);
+    let res = send_to(&iface, format!("REFRESH{}job{}a{}{}", SEP, SEP, SEP, u64::MAX));
+    assert_eq!(token(res), first);
+    let res = send_to(&iface, format!("LOCK{}job{}{}{}a", SEP, SEP, u64::MAX, SEP));
+    assert_eq!(token(res), first);
+}
+
+#[test]
+fn queue_ack() {
+    let iface = start(4);
+    let res = send_to(&iface, format!("ENQUEUE{}jobs{}payload", SEP, SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_eq!(res, format!("OK{}1{}payload", SEP, SEP));
+    let res = send_to(&iface, format!("ACK{}jobs{}1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn queue_huge_timeout() {
+    let iface = start(4);
+    let _ = send_to(&iface, format!("ENQUEUE{}jobs{}payload", SEP, SEP));
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}{}", SEP, SEP, u64::MAX));
+    assert_eq!(res, format!("OK{}1{}payload", SEP, SEP));
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("ACK{}jobs{}1", SEP, SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn queue_nack() {
+    let iface = start(5);
+    let _ = send_to(&iface, format!("ENQUEUE{}jobs{}first", SEP, SEP));
+    let _ = send_to(&iface, format!("ENQUEUE{}jobs{}second", SEP, SEP));
+    let _ = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    let res = send_to(&iface, format!("NACK{}jobs{}1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_eq!(res, format!("OK{}1{}first", SEP, SEP));
+}
+
+#[test]
+fn queue_visibility_timeout() {
+    let iface = start(4);
+    let _ = send_to(&iface, format!("ENQUEUE{}jobs{}payload", SEP, SEP));
+    let _ = send_to(&iface, format!("DEQUEUE{}jobs{}50", SEP, SEP));
+    thread::sleep(Duration::from_millis(100));
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_eq!(res, format!("OK{}1{}payload", SEP, SEP));
+    let res = send_to(&iface, format!("ACK{}jobs{}2", SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn select_isolated() {
+    let iface = start(3);
+    let _ = send_to(&iface, format!("SELECT{}a{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("SELECT{}a{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("value".to_string()));
+}
+
+#[test]
+fn select_clear() {
+    let iface = start(4);
+    let _ = send_to(&iface, format!("SELECT{}a{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    let _ = send_to(&iface, format!("SELECT{}b{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    let _ = send_to(&iface, format!("SELECT{}a{}CLEAR", SEP, SEP));
+    let res = send_to(&iface, format!("SELECT{}b{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("value".to_string()));
+}
+
+#[test]
+fn select_without_command() {
+    let iface = start(1);
+    let res = send_to(&iface, format!("SELECT{}a", SEP));
+    assert_error(res);
+}
+
+#[test]
+fn range_unordered() {
+    let iface = start(1);
+    let res = send_to(&iface, format!("RANGE{}a{}b", SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn range() {
+    let iface = bootstrap_with(5, Config { engine: Engine::Ordered, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}m:3{}c", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}m:1{}a", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}m:2{}b", SEP, SEP));
+    let res = send_to(&iface, format!("RANGE{}m:1{}m:3", SEP, SEP));
+    assert_eq!(res, format!("OK{}m:1{}a{}m:2{}b", SEP, SEP, SEP, SEP));
+    let res = send_to(&iface, format!("RANGE{}m:1{}m:9{}LIMIT{}1", SEP, SEP, SEP, SEP));
+    assert_eq!(res, format!("OK{}m:1{}a", SEP, SEP));
+}
+
+#[test]
+fn prefix() {
+    let iface = bootstrap_with(4, Config { engine: Engine::Ordered, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}m:1{}a", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}n:1{}b", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}m:2{}c", SEP, SEP));
+    let res = send_to(&iface, format!("PREFIX{}m:", SEP));
+    assert_eq!(res, format!("OK{}m:1{}a{}m:2{}c", SEP, SEP, SEP, SEP));
+}
+
+#[test]
+fn sharded_engine() {
+    let iface = bootstrap_with(3, Config { engine: Engine::Sharded(4), ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+    let res = send_to(&iface, format!("PREFIX{}k", SEP));
+    assert_error(res);
+}
+
+#[test]
+fn mapped_storage() {
+    let path = temp_path("mapped_storage.map");
+    {
+        let storage = MappedStorage::open(&path).unwrap();
+        // enough to grow both the table and the file
+        for i in 0..5000 {
+            assert_eq!(storage.insert(format!("key{}", i), "x".repeat(300)).unwrap(), None);
+        }
+        assert_eq!(storage.insert("key1".to_string(), "new".to_string()).unwrap(), Some("x".repeat(300)));
+        for i in 2..4000 {
+            assert!(storage.remove(&format!("key{}", i)).unwrap().is_some());
+        }
+        assert_eq!(storage.remove("key2").unwrap(), None);
+    }
+
+    let storage = MappedStorage::open(&path).unwrap();
+    assert_eq!(storage.len(), 1002);
+    assert_eq!(storage.get("key0"), Some("x".repeat(300)));
+    assert_eq!(storage.get("key1"), Some("new".to_string()));
+    assert_eq!(storage.get("key2"), None);
+    assert_eq!(storage.get("key4999"), Some("x".repeat(300)));
+
+    assert_eq!(storage.clear().unwrap().len(), 1002);
+    assert!(storage.is_empty());
+    assert_eq!(storage.get("key0"), None);
+}
+
+#[test]
+fn mapped_storage_churn() {
+    let path = temp_path("mapped_storage_churn.map");
+    let storage = MappedStorage::open(&path).unwrap();
+    let initial = std::fs::metadata(&path).unwrap().len();
+
+    // removed slots are reclaimed instead of growing the table
+    for i in 0..5000 {
+        storage.insert(format!("key{}", i), "value".to_string()).unwrap();
+        storage.remove(&format!("key{}", i)).unwrap();
+    }
+    storage.insert("key".to_string(), "value".to_string()).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), initial);
+    assert_eq!(storage.len(), 1);
+}
+
+#[test]
+fn mapped_engine() {
+    let engine = Engine::Mapped(temp_path("mapped_engine").into());
+
+    let iface = bootstrap_with(1, Config { engine: engine.clone(), ..Config::default() });
+    let _ = send_to(&iface, format!("SELECT{}other db{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+
+    // persisted databases are back after a restart, before being selected
+    let config = Config { engine, backup_dir: Some(temp_path("mapped_engine.backups")), ..Config::default() };
+    let iface = bootstrap_with(3, config);
+    let res = send_to(&iface, format!("BACKUP{}snapshot", SEP));
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(200));
+    let res = send_to(&iface, "BACKUP".to_string());
+    assert_eq!(res.split(SEP).nth(4), Some("1"));
+    let res = send_to(&iface, format!("SELECT{}other db{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("value".to_string()));
+}
+
+#[test]
+fn mapped_errors() {
+    let dir = temp_path("mapped_errors");
+    let engine = Engine::Mapped(dir.clone().into());
+    let iface = bootstrap_with(5, Config { engine, ..Config::default() });
+
+    // reading a database does not create its file, creating one too long for
+    // the file system fails without taking down the server
+    let name = "n".repeat(200);
+    let res = send_to(&iface, format!("SELECT{}{}{}GET{}key", SEP, name, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("SELECT{}db{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, None);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    let res = send_to(&iface, format!("SELECT{}{}{}INSERT{}key{}value", SEP, name, SEP, SEP, SEP));
+    assert_error(res);
+    let res = send_to(&iface, format!("SELECT{}db{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("SELECT{}db{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("value".to_string()));
+
+    // a slot pointing outside of the file is detected when opening it
+    let path = temp_path("mapped_errors.map");
+    MappedStorage::open(&path).unwrap().insert("key".to_string(), "value".to_string()).unwrap();
+    let mut data = std::fs::read(&path).unwrap();
+    let slot = (64..64 + 1024 * 8).step_by(8).find(|&at| data[at..at + 8] != [0; 8]).unwrap();
+    data[slot..slot + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    std::fs::write(&path, data).unwrap();
+    assert!(MappedStorage::open(&path).is_err());
+}
+
+/// Counts the writes reaching the wrapped storage.
+struct CountingStorage {
+    inner: HashStorage,
+    writes: Arc<AtomicUsize>
+}
+
+impl Storage for CountingStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        self.inner.get(key)
+    }
+
+    fn insert(&self, key: String, value: String) -> io::Result<Option<String>> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&self, key: &str) -> io::Result<Option<String>> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.remove(key)
+    }
+
+    fn clear(&self) -> io::Result<Vec<String>> {
+        self.inner.clear()
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn entries(&self) -> Vec<(String, String)> {
+        self.inner.entries()
+    }
+}
+
+#[test]
+fn custom_engine() {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&writes);
+    let engine = Engine::Custom(Arc::new(move || {
+        Box::new(CountingStorage { inner: HashStorage::new(), writes: Arc::clone(&counter) })
+    }));
+
+    let iface = bootstrap_with(3, Config { engine, ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, format!("REMOVE{}key", SEP));
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, None);
+    assert_eq!(writes.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn save_without_path() {
+    let iface = start(1);
+    let res = send_to(&iface, "SAVE".to_string());
+    assert_error(res);
+}
+
+#[test]
+fn save_and_load() {
+    let path = temp_path("save_and_load.snapshot");
+    let config = || Config { snapshot: Some(path.clone()), ..Config::default() };
+
+    let iface = bootstrap_with(3, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, format!("SELECT{}other{}INSERT{}key{}other_value", SEP, SEP, SEP, SEP));
+    let res = send_to(&iface, "SAVE".to_string());
+
+    // the checksum ends the file
+    let data = std::fs::read(&path).unwrap();
+    let checksum = u32::from_le_bytes([data[data.len() - 4], data[data.len() - 3], data[data.len() - 2], data[data.len() - 1]]);
+    assert_ok(res, Some(format!("{:08x}", checksum)));
+
+    let iface = bootstrap_with(2, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+    let res = send_to(&iface, format!("SELECT{}other{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("other_value".to_string()));
+}
+
+#[test]
+fn bgsave() {
+    let path = temp_path("bgsave.snapshot");
+    let config = || Config { snapshot: Some(path.clone()), ..Config::default() };
+
+    let iface = bootstrap_with(2, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let res = send_to(&iface, "BGSAVE".to_string());
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(200));
+
+    let iface = bootstrap_with(1, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+}
+
+#[test]
+fn aof_replay() {
+    let path = temp_path("aof_replay.aof");
+    let config = || Config { aof: Some(path.clone()), fsync: Fsync::Always, ..Config::default() };
+
+    let iface = bootstrap_with(5, config());
+    let _ = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}b{}2", SEP, SEP));
+    let _ = send_to(&iface, format!("REMOVE{}a", SEP));
+    let _ = send_to(&iface, format!("SELECT{}other{}INSERT{}c{}3", SEP, SEP, SEP, SEP));
+    let _ = send_to(&iface, format!("SELECT{}other{}CLEAR", SEP, SEP));
+
+    let iface = bootstrap_with(3, config());
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}b", SEP));
+    assert_ok(res, Some("2".to_string()));
+    let res = send_to(&iface, format!("SELECT{}other{}GET{}c", SEP, SEP, SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn aof_after_snapshot() {
+    let snapshot = temp_path("aof_after_snapshot.snapshot");
+    let aof = temp_path("aof_after_snapshot.aof");
+    let config = || Config {
+        snapshot: Some(snapshot.clone()),
+        aof: Some(aof.clone()),
+        fsync: Fsync::Never,
+        ..Config::default()
+    };
+
+    let iface = bootstrap_with(3, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, "SAVE".to_string());
+    let _ = send_to(&iface, format!("INSERT{}key{}new_value", SEP, SEP));
+
+    let iface = bootstrap_with(1, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("new_value".to_string()));
+}
+
+#[test]
+fn aof_rewrite() {
+    let path = temp_path("aof_rewrite.aof");
+    let config = || Config { aof: Some(path.clone()), aof_rewrite_ratio: 0.0, ..Config::default() };
+
+    let iface = bootstrap_with(6, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}key{}2", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}key{}3", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}other{}value", SEP, SEP));
+    let _ = send_to(&iface, format!("REMOVE{}other", SEP));
+    let before = std::fs::metadata(&path).unwrap().len();
+    let res = send_to(&iface, "BGREWRITEAOF".to_string());
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(200));
+    assert!(std::fs::metadata(&path).unwrap().len() < before);
+
+    let iface = bootstrap_with(2, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("3".to_string()));
+    let res = send_to(&iface, format!("GET{}other", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn aof_rewrite_after_snapshot() {
+    let snapshot = temp_path("aof_rewrite_after_snapshot.snapshot");
+    let aof = temp_path("aof_rewrite_after_snapshot.aof");
+    let config = || Config {
+        snapshot: Some(snapshot.clone()),
+        aof: Some(aof.clone()),
+        aof_rewrite_ratio: 0.0,
+        ..Config::default()
+    };
+
+    let iface = bootstrap_with(4, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}1", SEP, SEP));
+    let _ = send_to(&iface, "SAVE".to_string());
+    let _ = send_to(&iface, format!("REMOVE{}key", SEP));
+    let res = send_to(&iface, "BGREWRITEAOF".to_string());
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(200));
+
+    let iface = bootstrap_with(1, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn aof_rewrite_automatic() {
+    let path = temp_path("aof_rewrite_automatic.aof");
+    let config = Config {
+        aof: Some(path.clone()),
+        aof_rewrite_ratio: 2.0,
+        aof_rewrite_min_size: 0,
+        ..Config::default()
+    };
+
+    let iface = bootstrap_with(20, config);
+    for i in 0..20 {
+        let _ = send_to(&iface, format!("INSERT{}key{}{}", SEP, SEP, i));
+    }
+    let before = std::fs::metadata(&path).unwrap().len();
+    thread::sleep(Duration::from_millis(1500));
+    assert!(std::fs::metadata(&path).unwrap().len() < before);
+}
+
+#[test]
+fn aof_torn_tail() {
+    let path = temp_path("aof_torn_tail.aof");
+    let config = || Config { aof: Some(path.clone()), fsync: Fsync::Always, ..Config::default() };
+
+    let iface = bootstrap_with(1, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    // a record cut off in the middle of its payload
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[20, 0, 0, 0, 1, 2, 3, 4, 1]).unwrap();
+
+    let iface = bootstrap_with(1, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+}
+
+#[test]
+fn aof_corruption_degraded() {
+    let path = temp_path("aof_corruption_degraded.aof");
+    let config = || Config {
+        aof: Some(path.clone()),
+        fsync: Fsync::Always,
+        recovery: Recovery::Degraded,
+        ..Config::default()
+    };
+
+    let iface = bootstrap_with(2, config());
+    let _ = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    let len = std::fs::metadata(&path).unwrap().len();
+    let _ = send_to(&iface, format!("INSERT{}b{}2", SEP, SEP));
+
+    // flip the last byte of the first record
+    let mut data = std::fs::read(&path).unwrap();
+    data[len as usize - 1] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+
+    let iface = bootstrap_with(2, config());
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}b", SEP));
+    assert_ok(res, None);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    assert_eq!(std::fs::read(format!("{}.corrupt", path)).unwrap(), data);
+}
+
+#[test]
+fn aof_corrupt_length() {
+    let path = temp_path("aof_corrupt_length.aof");
+    let config = || Config {
+        aof: Some(path.clone()),
+        fsync: Fsync::Always,
+        recovery: Recovery::Degraded,
+        ..Config::default()
+    };
+
+    let iface = bootstrap_with(3, config());
+    let _ = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    let len = std::fs::metadata(&path).unwrap().len() as usize;
+    let _ = send_to(&iface, format!("INSERT{}b{}2", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}c{}3", SEP, SEP));
+
+    // a length in the middle running past the end is corruption, not a torn
+    // tail, so the log is kept for inspection
+    let mut data = std::fs::read(&path).unwrap();
+    data[len..len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, &data).unwrap();
+
+    let iface = bootstrap_with(2, config());
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&iface, format!("GET{}c", SEP));
+    assert_ok(res, None);
+    assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, len);
+    assert_eq!(std::fs::read(format!("{}.corrupt", path)).unwrap(), data);
+}
+
+#[test]
+fn snapshot_corruption_degraded() {
+    let path = temp_path("snapshot_corruption_degraded.snapshot");
+    let config = || Config { snapshot: Some(path.clone()), recovery: Recovery::Degraded, ..Config::default() };
+
+    let iface = bootstrap_with(2, config());
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, "SAVE".to_string());
+
+    // flip the last byte of the end record, which precedes the 4 byte trailer
+    let mut data = std::fs::read(&path).unwrap();
+    let end = data.len() - 5;
+    data[end] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+
+    let iface = bootstrap_with(1, config());
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+}
+
+// each of these entries accounts for 68 bytes
+const ENTRY: usize = 68;
+
+#[test]
+fn backup() {
+    let dir = temp_path("backups");
+    let path = std::path::Path::new(&dir).join("full.snapshot").to_str().unwrap().to_string();
+
+    let iface = bootstrap_with(7, Config { backup_dir: Some(dir), ..Config::default() });
+    let _ = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    let _ = send_to(&iface, format!("SELECT{}other{}INSERT{}key{}other_value", SEP, SEP, SEP, SEP));
+    let res = send_to(&iface, "BACKUP".to_string());
+    assert_error(res);
+
+    // backups stay inside the backup directory
+    let res = send_to(&iface, format!("BACKUP{}{}", SEP, temp_path("backup.snapshot")));
+    assert_error(res);
+    let res = send_to(&iface, format!("BACKUP{}../backup.snapshot", SEP));
+    assert_error(res);
+
+    let res = send_to(&iface, format!("BACKUP{}full.snapshot", SEP));
+    assert_ok(res, None);
+
+    thread::sleep(Duration::from_millis(200));
+    let res = send_to(&iface, "BACKUP".to_string());
+    let split: Vec<&str> = res.split(SEP).collect();
+    assert_eq!(split[..5], ["OK", "done", &path, "2", "2"]);
+    assert_eq!(split[5].len(), 8);
+
+    let iface = bootstrap_with(2, Config { snapshot: Some(path), ..Config::default() });
+    let res = send_to(&iface, format!("GET{}key", SEP));
+    assert_ok(res, Some("value".to_string()));
+    let res = send_to(&iface, format!("SELECT{}other{}GET{}key", SEP, SEP, SEP));
+    assert_ok(res, Some("other_value".to_string()));
+}
+
+#[test]
+fn memory_noeviction() {
+    let config = Config { max_memory: Some(2 * ENTRY), ..Config::default() };
+
+    let iface = bootstrap_with(6, config);
+    let res = send_to(&iface, format!("INSERT{}k1{}v1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("SELECT{}other{}INSERT{}k2{}v2", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("INSERT{}k3{}v3", SEP, SEP));
+    assert_eq!(res, format!("ERR{}Memory limit reached", SEP));
+
+    // replacing a value of the same size still fits
+    let res = send_to(&iface, format!("INSERT{}k1{}v9", SEP, SEP));
+    assert_ok(res, Some("v1".to_string()));
+    let res = send_to(&iface, format!("GET{}k3", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, "MEMORY".to_string());
+    assert_eq!(res, format!("OK{}{}{}{}", SEP, 2 * ENTRY, SEP, 2 * ENTRY));
+}
+
+#[test]
+fn memory_lru() {
+    let config = Config { max_memory: Some(3 * ENTRY), eviction: Eviction::AllKeysLru, ..Config::default() };
+
+    let iface = bootstrap_with(7, config);
+    let _ = send_to(&iface, format!("INSERT{}k1{}v1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}k2{}v2", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}k3{}v3", SEP, SEP));
+    let _ = send_to(&iface, format!("GET{}k1", SEP));
+    let res = send_to(&iface, format!("INSERT{}k4{}v4", SEP, SEP));
+    assert_ok(res, None);
+
+    let res = send_to(&iface, format!("GET{}k2", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}k1", SEP));
+    assert_ok(res, Some("v1".to_string()));
+}
+
+#[test]
+fn memory_lfu() {
+    let config = Config { max_memory: Some(2 * ENTRY), eviction: Eviction::AllKeysLfu, ..Config::default() };
+
+    let iface = bootstrap_with(8, config);
+    let _ = send_to(&iface, format!("INSERT{}k1{}v1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}k2{}v2", SEP, SEP));
+    let _ = send_to(&iface, format!("GET{}k1", SEP));
+    let _ = send_to(&iface, format!("GET{}k1", SEP));
+    let _ = send_to(&iface, format!("GET{}k2", SEP));
+
+    // k2 was read last, but less often
+    let res = send_to(&iface, format!("INSERT{}k3{}v3", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}k2", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}k1", SEP));
+    assert_ok(res, Some("v1".to_string()));
+}
+
+#[test]
+fn memory_accounting() {
+    let config = Config { max_memory: Some(4 * ENTRY), history: 1, ..Config::default() };
+
+    // an entry with its version, a message and a lease
+    let (version, message, lease) = (2 + 48, 1 + 48, 3 + 1 + 64);
+    let iface = bootstrap_with(11, config);
+    let _ = send_to(&iface, format!("INSERT{}k1{}v1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}k1{}v2", SEP, SEP));
+    let _ = send_to(&iface, format!("ENQUEUE{}jobs{}p", SEP, SEP));
+    let _ = send_to(&iface, format!("LOCK{}job{}10000{}a", SEP, SEP, SEP));
+    let res = send_to(&iface, "MEMORY".to_string());
+    assert_eq!(res, format!("OK{}{}{}{}", SEP, ENTRY + version + message + lease, SEP, 4 * ENTRY));
+
+    let res = send_to(&iface, format!("ENQUEUE{}jobs{}{}", SEP, SEP, "x".repeat(100)));
+    assert_eq!(res, format!("ERR{}Memory limit reached", SEP));
+
+    let _ = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    let res = send_to(&iface, format!("ACK{}jobs{}1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("UNLOCK{}job{}a", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, "MEMORY".to_string());
+    assert_eq!(res, format!("OK{}{}{}{}", SEP, ENTRY + version, SEP, 4 * ENTRY));
+}
+
+#[test]
+fn memory_random() {
+    let config = Config { max_memory: Some(2 * ENTRY), eviction: Eviction::Random, ..Config::default() };
+
+    let iface = bootstrap_with(5, config);
+    let _ = send_to(&iface, format!("INSERT{}k1{}v1", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}k2{}v2", SEP, SEP));
+    let res = send_to(&iface, format!("INSERT{}k3{}v3", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("GET{}k3", SEP));
+    assert_ok(res, Some("v3".to_string()));
+    let res = send_to(&iface, "MEMORY".to_string());
+    assert_eq!(res, format!("OK{}{}{}{}", SEP, 2 * ENTRY, SEP, 2 * ENTRY));
+}
+
+#[test]
+fn memory_random_without_entries() {
+    let config = Config { max_memory: Some(500), eviction: Eviction::Random, ..Config::default() };
+
+    // queued messages are never evicted, so there is nothing to pick from
+    let iface = bootstrap_with(14, config);
+    for _ in 0..12 {
+        let _ = send_to(&iface, format!("ENQUEUE{}jobs{}payload", SEP, SEP));
+    }
+    let res = send_to(&iface, format!("INSERT{}key{}value", SEP, SEP));
+    assert_eq!(res, format!("ERR{}Memory limit reached", SEP));
+    let res = send_to(&iface, format!("DEQUEUE{}jobs{}10000", SEP, SEP));
+    assert_eq!(res, format!("OK{}1{}payload", SEP, SEP));
+}
+
+#[test]
+fn replication() {
+    let leader = start(4);
+    let _ = send_to(&leader, format!("INSERT{}a{}1", SEP, SEP));
+    let _ = send_to(&leader, format!("SELECT{}other{}INSERT{}c{}3", SEP, SEP, SEP, SEP));
+
+    // the follower's connection counts towards the leader's requests
+    let follower = bootstrap_with(5, Config { replica_of: Some(leader.clone()), ..Config::default() });
+    let res = send_to(&follower, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+
+    let _ = send_to(&leader, format!("REMOVE{}a", SEP));
+    thread::sleep(Duration::from_millis(100));
+
+    let res = send_to(&follower, format!("GET{}a", SEP));
+    assert_ok(res, None);
+    let res = send_to(&follower, format!("SELECT{}other{}GET{}c", SEP, SEP, SEP));
+    assert_ok(res, Some("3".to_string()));
+    let res = send_to(&follower, format!("INSERT{}b{}2", SEP, SEP));
+    assert_error(res);
+    let res = send_to(&follower, format!("SELECT{}other{}CLEAR", SEP, SEP));
+    assert_error(res);
+}
+
+#[test]
+fn auth() {
+    let iface = bootstrap_with(5, Config { requirepass: Some("secret".to_string()), ..Config::default() });
+    let res = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    assert_eq!(res, format!("ERR{}Authentication required", SEP));
+    let res = send_to(&iface, format!("AUTH{}wrong{}INSERT{}a{}1", SEP, SEP, SEP, SEP));
+    assert_eq!(res, format!("ERR{}Invalid password", SEP));
+    let res = send_to(&iface, format!("AUTH{}secret{}SELECT{}db{}INSERT{}a{}1", SEP, SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("AUTH{}secret{}SELECT{}db{}GET{}a", SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&iface, "SAVE".to_string());
+    assert_eq!(res, format!("ERR{}Authentication required", SEP));
+}
+
+#[test]
+fn auth_replication() {
+    let config = || Config { requirepass: Some("secret".to_string()), ..Config::default() };
+    let leader = bootstrap_with(2, config());
+    let _ = send_to(&leader, format!("AUTH{}secret{}INSERT{}a{}1", SEP, SEP, SEP, SEP));
+
+    let follower = bootstrap_with(1, Config { replica_of: Some(leader), ..config() });
+    let res = send_to(&follower, format!("AUTH{}secret{}GET{}a", SEP, SEP, SEP));
+    assert_ok(res, Some("1".to_string()));
+}
+
+#[test]
+fn acl() {
+    let path = temp_path("acl");
+    std::fs::write(&path, "# users\nuser ops >secret +@all +@admin ~*\nuser team >pw +@all -CLEAR ~team:*\nuser reader >pw +GET %R~*\n").unwrap();
+
+    let iface = bootstrap_with(14, Config { acl_file: Some(path.clone()), ..Config::default() });
+    let user = |name: &str, request: String| format!("USER{}{}{}pw{}{}", SEP, name, SEP, SEP, request);
+
+    let res = send_to(&iface, format!("GET{}team:a", SEP));
+    assert_eq!(res, format!("ERR{}Authentication required", SEP));
+    let res = send_to(&iface, format!("USER{}team{}wrong{}GET{}team:a", SEP, SEP, SEP, SEP));
+    assert_eq!(res, format!("ERR{}Invalid username or password", SEP));
+
+    let res = send_to(&iface, user("team", format!("SELECT{}db{}INSERT{}team:a{}1", SEP, SEP, SEP, SEP)));
+    assert_ok(res, None);
+    let res = send_to(&iface, user("team", format!("INSERT{}other:a{}1", SEP, SEP)));
+    assert_error(res);
+    let res = send_to(&iface, user("team", "CLEAR".to_string()));
+    assert_eq!(res, format!("ERR{}User team may not run CLEAR", SEP));
+
+    // admin commands are not part of +@all
+    let res = send_to(&iface, user("team", format!("REPLICAOF{}127.0.0.1:1", SEP)));
+    assert_eq!(res, format!("ERR{}User team may not run REPLICAOF", SEP));
+    let res = send_to(&iface, user("team", format!("ACL{}LOAD", SEP)));
+    assert_eq!(res, format!("ERR{}User team may not run ACL", SEP));
+
+    let res = send_to(&iface, user("reader", format!("SELECT{}db{}GET{}team:a", SEP, SEP, SEP)));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&iface, user("reader", format!("INSERT{}team:a{}2", SEP, SEP)));
+    assert_error(res);
+
+    let res = send_to(&iface, format!("USER{}ops{}secret{}SELECT{}db{}CLEAR", SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+
+    // reloading applies changed permissions
+    std::fs::write(&path, "user ops >secret +@all +@admin ~*\nuser team >pw +@all ~team:*\n").unwrap();
+    let res = send_to(&iface, format!("USER{}ops{}secret{}ACL{}LOAD", SEP, SEP, SEP, SEP));
+    assert_ok(res, Some("2".to_string()));
+    let res = send_to(&iface, format!("USER{}ops{}secret{}ACL{}LIST", SEP, SEP, SEP, SEP));
+    assert_eq!(res, format!("OK{}ops{}team", SEP, SEP));
+    let res = send_to(&iface, user("reader", format!("GET{}team:a", SEP)));
+    assert_error(res);
+    let res = send_to(&iface, user("team", "CLEAR".to_string()));
+    assert_error(res);
+}
+
+#[test]
+fn read_only() {
+    let iface = bootstrap_with(11, Config { read_only: true, ..Config::default() });
+    let res = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    assert_eq!(res, format!("ERR{}READONLY Server is in read-only mode", SEP));
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, None);
+
+    let res = send_to(&iface, format!("READONLY{}OFF", SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("READONLY{}ON", SEP));
+    assert_ok(res, None);
+
+    let res = send_to(&iface, format!("REMOVE{}a", SEP));
+    assert_eq!(res, format!("ERR{}READONLY Server is in read-only mode", SEP));
+    let res = send_to(&iface, format!("ENQUEUE{}jobs{}payload", SEP, SEP));
+    assert_error(res);
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+
+    // commands replacing data or ownership are writes as well
+    let res = send_to(&iface, format!("REPLICAOF{}127.0.0.1:1", SEP));
+    assert_eq!(res, format!("ERR{}READONLY Server is in read-only mode", SEP));
+    let mut stream = TcpStream::connect(&iface).unwrap();
+    stream.write_all(format!("RESTORE{}jsonl{}{}", SEP, SEP, r#"{"key":"b","value":"2"}"#).as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert_eq!(res, format!("ERR{}READONLY Server is in read-only mode", SEP));
+    let res = send_to(&iface, format!("SETSLOT{}0{}NODE{}127.0.0.1:1", SEP, SEP, SEP));
+    assert_eq!(res, format!("ERR{}READONLY Server is in read-only mode", SEP));
+}
+
+#[test]
+fn audit_log() {
+    let acl = temp_path("audit-acl");
+    std::fs::write(&acl, "user ops >secret +@all +@admin ~*\nuser team >pw +@all -CLEAR ~*\n").unwrap();
+    let path = temp_path("audit");
+
+    let config = Config { acl_file: Some(acl), audit_log: Some(path.clone()), audit_values: true, ..Config::default() };
+    let iface = bootstrap_with(6, config);
+
+    let res = send_to(&iface, format!("USER{}team{}pw{}SELECT{}db{}INSERT{}a{}1", SEP, SEP, SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("USER{}team{}pw{}GET{}a", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("USER{}team{}pw{}CLEAR", SEP, SEP, SEP));
+    assert_error(res);
+    let res = send_to(&iface, format!("USER{}ops{}secret{}SELECT{}db{}REMOVE{}a", SEP, SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&iface, format!("USER{}ops{}secret{}READONLY{}OFF", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&iface, format!("USER{}ops{}secret{}SELECT{}db{}CLEAR", SEP, SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+
+    // reads are not recorded
+    let log = std::fs::read_to_string(&path).unwrap();
+    let entries: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(entries.len(), 5);
+
+    assert_eq!(entries[0]["user"], "team");
+    assert_eq!(entries[0]["db"], "db");
+    assert_eq!(entries[0]["command"], "INSERT");
+    assert_eq!(entries[0]["key"], "a");
+    assert_eq!(entries[0]["value"], "1");
+    assert_eq!(entries[0]["ok"], true);
+    assert!(entries[0]["client"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert!(entries[0]["time"].as_u64().unwrap() > 0);
+
+    assert_eq!(entries[1]["command"], "CLEAR");
+    assert_eq!(entries[1]["error"], "User team may not run CLEAR");
+    assert_eq!(entries[2]["user"], "ops");
+    assert_eq!(entries[2]["command"], "REMOVE");
+    assert_eq!(entries[3]["command"], "READONLY");
+    assert_eq!(entries[3]["args"], serde_json::json!(["OFF"]));
+    assert_eq!(entries[4]["command"], "CLEAR");
+    assert_eq!(entries[4]["db"], "db");
+}
+
+#[test]
+fn client_rate_limit() {
+    let iface = bootstrap_with(5, Config { client_rate_limit: Some(3), ..Config::default() });
+    for _ in 0..3 {
+        let res = send_to(&iface, "TEST".to_string());
+        assert_ok(res, None);
+    }
+    let res = send_to(&iface, "TEST".to_string());
+    assert_eq!(res, format!("ERR{}RATELIMIT Client 127.0.0.1 exceeded 3 requests per second", SEP));
+
+    thread::sleep(Duration::from_millis(500));
+    let res = send_to(&iface, "TEST".to_string());
+    assert_ok(res, None);
+}
+
+#[test]
+fn rate_limit_peers() {
+    let config = || Config { requirepass: Some("secret".to_string()), client_rate_limit: Some(2), ..Config::default() };
+    let auth = |request: String| format!("AUTH{}secret{}{}", SEP, SEP, request);
+    let leader = bootstrap_with(4, config());
+    let res = send_to(&leader, auth(format!("INSERT{}a{}1", SEP, SEP)));
+    assert_ok(res, None);
+
+    // the replication stream of a follower is not charged
+    let follower = bootstrap_with(1, Config { replica_of: Some(leader.clone()), ..config() });
+    let res = send_to(&leader, auth(format!("GET{}a", SEP)));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&leader, auth(format!("GET{}a", SEP)));
+    assert_eq!(res, format!("ERR{}RATELIMIT Client 127.0.0.1 exceeded 2 requests per second", SEP));
+
+    let res = send_to(&follower, auth(format!("GET{}a", SEP)));
+    assert_ok(res, Some("1".to_string()));
+
+    // without a password anyone could pose as a node
+    let iface = bootstrap_with(2, Config { client_rate_limit: Some(1), ..Config::default() });
+    let _ = send_to(&iface, "TEST".to_string());
+    let res = send_to(&iface, format!("RAFT{}VOTE", SEP));
+    assert_eq!(res, format!("ERR{}RATELIMIT Client 127.0.0.1 exceeded 1 requests per second", SEP));
+}
+
+#[test]
+fn global_rate_limit() {
+    let iface = bootstrap_with(3, Config { rate_limit: Some(2), ..Config::default() });
+    let _ = send_to(&iface, "TEST".to_string());
+    let _ = send_to(&iface, "TEST".to_string());
+    let res = send_to(&iface, format!("INSERT{}a{}1", SEP, SEP));
+    assert_eq!(res, format!("ERR{}RATELIMIT Server exceeded 2 requests per second", SEP));
+}
+
+#[test]
+fn user_rate_limit() {
+    let path = temp_path("user_rate_limit.acl");
+    std::fs::write(&path, "user a >pw +@all ~*\nuser b >pw +@all ~*\n").unwrap();
+    let iface = bootstrap_with(4, Config { acl_file: Some(path), user_rate_limit: Some(2), ..Config::default() });
+
+    let user = |name: &str| format!("USER{}{}{}pw{}TEST", SEP, name, SEP, SEP);
+    let _ = send_to(&iface, user("a"));
+    let _ = send_to(&iface, user("a"));
+    let res = send_to(&iface, user("a"));
+    assert_eq!(res, format!("ERR{}RATELIMIT User a exceeded 2 requests per second", SEP));
+    let res = send_to(&iface, user("b"));
+    assert_ok(res, None);
+}
+
+#[test]
+fn cidr() {
+    let cidrs = yocto::args::parse_cidrs("10.0.0.0/8, 192.168.1.1,::1,fd00::/8").unwrap();
+    let contains = |ip: &str| cidrs.iter().any(|cidr: &Cidr| cidr.contains(ip.parse().unwrap()));
+    assert!(contains("10.1.2.3"));
+    assert!(!contains("11.0.0.1"));
+    assert!(contains("192.168.1.1"));
+    assert!(!contains("192.168.1.2"));
+    assert!(contains("::1"));
+    assert!(contains("fd12::1"));
+    assert!(contains("::ffff:10.0.0.1"));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn deny_connections() {
+    let deny = yocto::args::parse_cidrs("127.0.0.0/8").unwrap();
+    let iface = bootstrap_with(1, Config { deny, ..Config::default() });
+    assert_refused(&iface);
+
+    let allow = yocto::args::parse_cidrs("10.0.0.0/8").unwrap();
+    let iface = bootstrap_with(1, Config { allow, ..Config::default() });
+    assert_refused(&iface);
+
+    let allow = yocto::args::parse_cidrs("10.0.0.0/8,127.0.0.1").unwrap();
+    let iface = bootstrap_with(1, Config { allow, ..Config::default() });
+    let res = send_to(&iface, "TEST".to_string());
+    assert_ok(res, None);
+}
+
+#[test]
+fn promotion() {
+    let leader = start(5);
+    let _ = send_to(&leader, format!("INSERT{}a{}1", SEP, SEP));
+    let first = bootstrap_with(4, Config { replica_of: Some(leader.clone()), ..Config::default() });
+    let second = bootstrap_with(4, Config { replica_of: Some(leader.clone()), history: 10, ..Config::default() });
+    let _ = send_to(&leader, format!("INSERT{}b{}2", SEP, SEP));
+    thread::sleep(Duration::from_millis(100));
+
+    // the first follower has caught up with the leader
+    let res = send_to(&leader, "ROLE".to_string());
+    let role = send_to(&first, "ROLE".to_string());
+    let split: Vec<&str> = role.split(SEP).collect();
+    assert_eq!(split[..3], ["OK", "follower", leader.as_str()]);
+    assert_eq!(res, format!("OK{}leader{}{}{}{}", SEP, SEP, split[3], SEP, split[4]));
+    assert_eq!(split[4], "2");
+
+    let res = send_to(&first, "PROMOTE".to_string());
+    assert_ok(res, None);
+    let res = send_to(&second, format!("REPLICAOF{}{}", SEP, first));
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(100));
+
+    let res = send_to(&first, format!("INSERT{}c{}3", SEP, SEP));
+    assert_ok(res, None);
+    thread::sleep(Duration::from_millis(100));
+
+    let res = send_to(&second, format!("GET{}c", SEP));
+    assert_ok(res, Some("3".to_string()));
+
+    // a full resync would have cleared and reinserted the key
+    let res = send_to(&second, format!("HISTORY{}a", SEP));
+    assert_eq!(res.split(SEP).count(), 3);
+    let res = send_to(&second, "ROLE".to_string());
+    assert!(res.ends_with(&format!("{}3", SEP)));
+}
+
+#[test]
+fn cluster() {
+    let members = start_cluster("cluster", 3, &[]);
+
+    let leader = send_to_leader(&members, format!("INSERT{}a{}1", SEP, SEP));
+    let res = send_to(&leader, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&leader, format!("REMOVE{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&leader, format!("REMOVE{}a", SEP));
+    assert_eq!(res, format!("ERR{}Key not found: a", SEP));
+    let res = send_to(&leader, format!("SELECT{}other{}INSERT{}b{}2", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&leader, format!("LOCK{}l{}owner{}1000", SEP, SEP, SEP));
+    assert_error(res);
+
+    // followers point clients to the leader
+    for member in members.iter().filter(|member| **member != leader) {
+        let res = send_to(member, format!("GET{}a", SEP));
+        assert!(res.starts_with("ERR") && res.ends_with(&leader));
+    }
+}
+
+#[test]
+fn cluster_member_down() {
+    let members = start_cluster("cluster_member_down", 3, &[2]);
+
+    let leader = send_to_leader(&members[..2], format!("INSERT{}a{}1", SEP, SEP));
+    let res = send_to(&leader, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+}
+
+#[test]
+fn sharding() {
+    let (first, second) = (next_iface(), next_iface());
+    let slots = yocto::args::parse_slots(&format!("0-8191={},8192-16383={}", first, second)).unwrap();
+    bootstrap_at(&first, 5, Config { slots: slots.clone(), ..Config::default() });
+    bootstrap_at(&second, 1, Config { slots, ..Config::default() });
+
+    let res = send_to(&first, format!("KEYSLOT{}key", SEP));
+    let slot: u16 = res.split(SEP).nth(1).unwrap().parse().unwrap();
+    let (owner, other) = if slot < 8192 { (&first, &second) } else { (&second, &first) };
+
+    let res = send_to(other, format!("SELECT{}db{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    assert_eq!(res, format!("ERR{}MOVED {} {}", SEP, slot, owner));
+    let res = send_to(owner, format!("SELECT{}db{}INSERT{}key{}value", SEP, SEP, SEP, SEP));
+    assert_ok(res, None);
+
+    // commands without a key are served locally
+    let res = send_to(&first, "TEST".to_string());
+    assert_ok(res, None);
+    let res = send_to(&first, "SLOTS".to_string());
+    assert_eq!(res, format!("OK{}0-8191 {}{}8192-16383 {}", SEP, first, SEP, second));
+}
+
+#[test]
+fn sharded_restore_and_range() {
+    // c and d are in slots 8047 and 2764, a is in slot 15939
+    let (first, second) = (next_iface(), next_iface());
+    let slots = yocto::args::parse_slots(&format!("0-8191={},8192-16383={}", first, second)).unwrap();
+    bootstrap_at(&first, 5, Config { slots, engine: Engine::Ordered, ..Config::default() });
+
+    let restore = |entries: &[(&str, &str)]| {
+        let data: String = entries.iter().map(|(k, v)| format!("{{\"key\":\"{}\",\"value\":\"{}\"}}\n", k, v)).collect();
+        let mut stream = TcpStream::connect(&first).unwrap();
+        stream.write_all(format!("RESTORE{}jsonl{}{}", SEP, SEP, data).as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    };
+    assert_eq!(restore(&[("c", "3"), ("a", "1")]), format!("ERR{}MOVED 15939 {}", SEP, second));
+    assert_ok(restore(&[("c", "3"), ("d", "4")]), Some("2".to_string()));
+
+    // d stays behind after its slot is handed over
+    let res = send_to(&first, format!("SETSLOT{}2764{}NODE{}{}", SEP, SEP, SEP, second));
+    assert_ok(res, None);
+    let res = send_to(&first, format!("RANGE{}a{}z", SEP, SEP));
+    assert_eq!(res, format!("OK{}c{}3", SEP, SEP));
+    let res = send_to(&first, format!("PREFIX{}d", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn slot_migration() {
+    let (source, target) = (next_iface(), next_iface());
+    let slots = yocto::args::parse_slots(&format!("0-16383={}", source)).unwrap();
+    bootstrap_at(&source, 8, Config { slots: slots.clone(), ..Config::default() });
+    bootstrap_at(&target, 6, Config { slots, ..Config::default() });
+
+    let res = send_to(&source, format!("KEYSLOT{}a", SEP));
+    let slot = res.split(SEP).nth(1).unwrap().to_string();
+    let _ = send_to(&source, format!("INSERT{}a{}1", SEP, SEP));
+
+    let res = send_to(&target, format!("SETSLOT{}{}{}IMPORTING{}{}", SEP, slot, SEP, SEP, source));
+    assert_ok(res, None);
+    let res = send_to(&source, format!("SETSLOT{}{}{}MIGRATING{}{}", SEP, slot, SEP, SEP, target));
+    assert_ok(res, None);
+
+    // keys still on the source are served there
+    let res = send_to(&source, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+
+    let res = send_to(&source, format!("MIGRATE{}{}", SEP, slot));
+    assert_ok(res, Some("1".to_string()));
+    let res = send_to(&source, format!("GET{}a", SEP));
+    assert_eq!(res, format!("ERR{}ASK {} {}", SEP, slot, target));
+    let res = send_to(&target, format!("GET{}a", SEP));
+    assert_eq!(res, format!("ERR{}MOVED {} {}", SEP, slot, source));
+    let res = send_to(&target, format!("ASKING{}GET{}a", SEP, SEP));
+    assert_ok(res, Some("1".to_string()));
+
+    let _ = send_to(&source, format!("SETSLOT{}{}{}NODE{}{}", SEP, slot, SEP, SEP, target));
+    let _ = send_to(&target, format!("SETSLOT{}{}{}NODE{}{}", SEP, slot, SEP, SEP, target));
+    let res = send_to(&source, format!("GET{}a", SEP));
+    assert_eq!(res, format!("ERR{}MOVED {} {}", SEP, slot, target));
+    let res = send_to(&target, format!("GET{}a", SEP));
+    assert_ok(res, Some("1".to_string()));
+}
+
+#[test]
+fn slot_migration_locks_and_queues() {
+    let (source, target) = (next_iface(), next_iface());
+    let slots = yocto::args::parse_slots(&format!("0-16383={}", source)).unwrap();
+    bootstrap_at(&source, 9, Config { slots: slots.clone(), ..Config::default() });
+    bootstrap_at(&target, 1, Config { slots, ..Config::default() });
+
+    let res = send_to(&source, format!("KEYSLOT{}job", SEP));
+    let slot = res.split(SEP).nth(1).unwrap().to_string();
+    let res = send_to(&source, format!("LOCK{}job{}10000{}a", SEP, SEP, SEP));
+    assert!(token(res) > 0);
+    let _ = send_to(&source, format!("ENQUEUE{}job{}payload", SEP, SEP));
+
+    let _ = send_to(&target, format!("SETSLOT{}{}{}IMPORTING{}{}", SEP, slot, SEP, SEP, source));
+    let _ = send_to(&source, format!("SETSLOT{}{}{}MIGRATING{}{}", SEP, slot, SEP, SEP, target));
+    let res = send_to(&source, format!("MIGRATE{}{}", SEP, slot));
+    assert_ok(res, Some("0".to_string()));
+
+    // the lease and the messages are not moved, so they are served by the source
+    let res = send_to(&source, format!("LOCK{}job{}10000{}b", SEP, SEP, SEP));
+    assert_eq!(res, format!("ERR{}Lock is held by another owner: job", SEP));
+    let res = send_to(&source, format!("DEQUEUE{}job{}10000", SEP, SEP));
+    assert_eq!(res, format!("OK{}1{}payload", SEP, SEP));
+    let res = send_to(&source, format!("ACK{}job{}1", SEP, SEP));
+    assert_ok(res, None);
+    let res = send_to(&source, format!("DEQUEUE{}job{}10000", SEP, SEP));
+    assert_eq!(res, format!("ERR{}ASK {} {}", SEP, slot, target));
+}
+
+#[test]
+fn dump() {
+    let iface = start(4);
+    let _ = send_to(&iface, format!("INSERT{}b{}2", SEP, SEP));
+    let _ = send_to(&iface, format!("INSERT{}a{}say \"hi\", bye", SEP, SEP));
+
+    let res = send_to(&iface, format!("DUMP{}jsonl", SEP));
+    assert_eq!(res, format!("OK{}{}\n{}\n", SEP,
+                            r#"{"key":"a","value":"say \"hi\", bye"}"#, r#"{"key":"b","value":"2"}"#));
+    let res = send_to(&iface, format!("DUMP{}csv", SEP));
+    assert_eq!(res, format!("OK{}key,value\na,\"say \"\"hi\"\", bye\"\nb,2\n", SEP));
+}
+
+#[test]
+fn restore_malformed() {
+    let iface = start(2);
+    let mut stream = TcpStream::connect(&iface).unwrap();
+    stream.write_all(format!("RESTORE{}jsonl{}{}\n{{bad", SEP, SEP, r#"{"key":"a","value":"1"}"#).as_bytes()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert_error(res);
+
+    // nothing is inserted
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn restore_without_shutdown() {
+    let iface = start(2);
+    let mut stream = TcpStream::connect(&iface).unwrap();
+    stream.write_all(format!("RESTORE{}jsonl{}{}", SEP, SEP, r#"{"key":"a","value":"1"}"#).as_bytes()).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert_eq!(res, format!("ERR{}Timed out reading the data of RESTORE", SEP));
+
+    let res = send_to(&iface, format!("GET{}a", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn export_import() {
+    let (source, target) = (next_iface(), next_iface());
+    bootstrap_at(&source, 2, Config::default());
+    bootstrap_at(&target, 3, Config::default());
+
+    // larger than a single read
+    let fixture = temp_path("fixture.csv");
+    let mut data = "key,value\n".to_string();
+    for i in 0..2000 {
+        data.push_str(&format!("key{},\"line one\nline, \"\"two\"\" {}\"\n", i, "x".repeat(300)));
+    }
+    std::fs::write(&fixture, data).unwrap();
+
+    let transfer = |iface: &str, db: &str, format, file: &str| Transfer {
+        iface: iface.to_string(), db: db.to_string(), format, file: Some(file.to_string()), password: None
+    };
+
+    let count = yocto::transfer::import(&transfer(&source, "db", Format::Csv, &fixture)).unwrap();
+    assert_eq!(count, 2000);
+
+    let archive = temp_path("archive.jsonl");
+    yocto::transfer::export(&transfer(&source, "db", Format::JsonLines, &archive)).unwrap();
+    let count = yocto::transfer::import(&transfer(&target, "db", Format::JsonLines, &archive)).unwrap();
+    assert_eq!(count, 2000);
+
+    let res = send_to(&target, format!("SELECT{}db{}GET{}key7", SEP, SEP, SEP));
+    assert_ok(res, Some(format!("line one\nline, \"two\" {}", "x".repeat(300))));
+    let res = send_to(&target, format!("GET{}key7", SEP));
+    assert_ok(res, None);
+}
+
+#[test]
+fn config_file() {
+    let path = temp_path("config.toml");
+    std::fs::write(&path, "threads = 2\nhistory = 3\nfsync = \"always\"\nmax_memory = 1024\nread_only = true\n\
+        cluster = [\"127.0.0.1:7101\", \"127.0.0.1:7102\"]\nallow = \"10.0.0.0/8\"\n").unwrap();
+    let vars = [("YOCTO_MAX_MEMORY", "2048"), ("YOCTO_HISTORY", "")];
+
+    // the command line takes precedence over the environment, which takes
+    // precedence over the file
+    let config = serve(&["yocto", "--config", &path, "--threads", "8", "--aof", "log"], &vars);
+    assert_eq!(config.threads, 8);
+    assert_eq!(config.max_memory, Some(2048));
+    assert_eq!(config.history, 3);
+    assert_eq!(config.fsync, Fsync::Always);
+    assert!(config.read_only);
+    assert_eq!(config.cluster, vec!["127.0.0.1:7101".to_string(), "127.0.0.1:7102".to_string()]);
+    assert_eq!(config.allow, vec!["10.0.0.0/8".parse::<Cidr>().unwrap()]);
+    assert_eq!(config.aof, Some("log".to_string()));
+    assert_eq!(config.iface, "127.0.0.1:7001");
+    assert_eq!(config.recovery, Recovery::Strict);
+
+    let config = serve(&["yocto", "--no-read-only"], &[("YOCTO_CONFIG", &path), ("YOCTO_READ_ONLY", "true")]);
+    assert!(!config.read_only);
+    assert_eq!(config.threads, 2);
+    let config = serve(&["yocto", "--config", &path], &[("YOCTO_READ_ONLY", "false")]);
+    assert!(!config.read_only);
+
+    let error = invalid(&["yocto", "--mapped-dir", "data"], &[]);
+    assert_eq!(error, "mapped-dir requires engine mapped");
+    let config = serve(&["yocto", "--mapped-dir", "data"], &[("YOCTO_ENGINE", "mapped")]);
+    assert!(matches!(config.engine, Engine::Mapped(ref dir) if dir == std::path::Path::new("data")));
+
+    let error = invalid(&["yocto", "--config", &path, "--threads", "many"], &[]);
+    assert_eq!(error, "Invalid --threads: invalid digit found in string");
+    let error = invalid(&["yocto", "--config", &path, "--threads", "0"], &[]);
+    assert_eq!(error, "threads must be at least 1");
+    let error = invalid(&["yocto", "--save-interval", "10"], &[]);
+    assert_eq!(error, "save-interval requires snapshot");
+    let error = invalid(&["yocto", "--slots", "0-16384=a:1"], &[]);
+    assert_eq!(error, "Invalid slot range 0-16384: slots range from 0 to 16383");
+    let error = invalid(&["yocto"], &[("YOCTO_SLOTS", "9-3=a:1")]);
+    assert_eq!(error, "Invalid slot range 9-3: slots range from 0 to 16383");
+    let error = invalid(&["yocto", "--cluster", "a:1,b:1,c:1", "--acl-file", "users.acl"], &[]);
+    assert_eq!(error, "cluster with acl-file requires requirepass");
+    let error = invalid(&["yocto", "--snapshot", "dump", "--save-interval", "0"], &[]);
+    assert_eq!(error, "save-interval must be at least 1");
+
+    std::fs::write(&path, "eviction = \"sometimes\"\n").unwrap();
+    let error = invalid(&["yocto", "--config", &path], &[]);
+    assert_eq!(error, format!("Invalid eviction in {}: Unknown eviction policy: sometimes", path));
+    std::fs::write(&path, "eviction = \"volatile-ttl\"\n").unwrap();
+    let config = serve(&["yocto", "--config", &path], &[]);
+    assert_eq!(config.eviction, Eviction::VolatileTtl);
+
+    std::fs::write(&path, "thread = 2\n").unwrap();
+    let error = invalid(&["yocto", "--config", &path], &[]);
+    assert_eq!(error, format!("{}: unknown option thread", path));
+
+    std::fs::write(&path, "threads = \n").unwrap();
+    let error = invalid(&["yocto", "--config", &path], &[]);
+    assert!(error.starts_with(&format!("{}: ", path)));
+
+    let error = invalid(&["yocto", "--config", &temp_path("missing.toml")], &[]);
+    assert!(error.starts_with("Failed to read"));
+}
+
 fn bootstrap(exit_after: usize) {
     let config = Config {
         threads: 1,
         iface: "127.0.0.1:7002".to_string(),
         log_level: LogLevelFilter::Error,
-        exit_after: Some(exit_after)
+        exit_after: Some(exit_after),
+        ..Config::default()
     };
 
-    let handle = thread::spawn(|| {
+    // servers on the fixed port take turns, each holding it until it exits
+    let (started, ready) = mpsc::channel();
+    thread::spawn(move || {
+        let _port = FIXED_PORT.lock().unwrap_or_else(|e| e.into_inner());
+        started.send(()).unwrap();
         yocto::run(config);
     });
+    ready.recv().unwrap();
 
     // give it some time to start
     thread::sleep(Duration::from_millis(200));
 }
 
 fn send(request: String) -> String {
-    let mut stream = TcpStream::connect("127.0.0.1:7002").unwrap();
+    send_to("127.0.0.1:7002", request)
+}
+
+fn start(exit_after: usize) -> String {
+    bootstrap_with(exit_after, Config::default())
+}
+
+fn bootstrap_with(exit_after: usize, config: Config) -> String {
+    let iface = next_iface();
+    bootstrap_at(&iface, exit_after, config);
+    iface
+}
+
+fn next_iface() -> String {
+    format!("127.0.0.1:{}", PORT.fetch_add(1, Ordering::SeqCst))
+}
+
+fn bootstrap_at(iface: &str, exit_after: usize, config: Config) {
+    let config = Config {
+        threads: 1,
+        iface: iface.to_string(),
+        log_level: LogLevelFilter::Error,
+        exit_after: Some(exit_after),
+        ..config
+    };
+
+    thread::spawn(|| {
+        yocto::run(config);
+    });
+
+    // give it some time to start
+    thread::sleep(Duration::from_millis(200));
+}
 
-    stream.write(request.as_bytes()).unwrap();
+fn send_to(iface: &str, request: String) -> String {
+    let mut stream = TcpStream::connect(iface).unwrap();
+
+    stream.write_all(request.as_bytes()).unwrap();
     stream.flush().unwrap();
 
     let mut buffer = [0; 512];
-    stream.read(&mut buffer);
+    let len = stream.read(&mut buffer).unwrap();
 
-    str::from_utf8(&buffer[..])
+    str::from_utf8(&buffer[..len])
         .unwrap()
         .trim_end_matches(char::from(0))
         .to_string()
 }
 
+/// Starts a cluster of `size` members, except for those at the indices in
+/// `down`, and returns the addresses of all members.
+fn start_cluster(name: &str, size: usize, down: &[usize]) -> Vec<String> {
+    let members: Vec<String> = (0..size)
+        .map(|_| next_iface())
+        .collect();
+
+    for (i, iface) in members.iter().enumerate().filter(|(i, _)| !down.contains(i)) {
+        let config = Config {
+            threads: 4,
+            iface: iface.clone(),
+            log_level: LogLevelFilter::Error,
+            cluster: members.clone(),
+            raft_dir: Some(temp_path(&format!("{}-{}", name, i))),
+            ..Config::default()
+        };
+        thread::spawn(|| yocto::run(config));
+    }
+
+    members
+}
+
+/// Sends `request` to each of `members` until one accepts it as the leader,
+/// and returns the leader's address.
+fn send_to_leader(members: &[String], request: String) -> String {
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        for member in members {
+            if send_to(member, request.clone()).starts_with("OK") {
+                return member.clone();
+            }
+        }
+    }
+    panic!("No leader elected.");
+}
+
+fn serve(args: &[&str], vars: &[(&str, &str)]) -> Config {
+    match args::parse(args, vars.iter().cloned()) {
+        Ok(Action::Serve(config)) => *config,
+        _ => panic!("No server configured.")
+    }
+}
+
+fn invalid(args: &[&str], vars: &[(&str, &str)]) -> String {
+    match args::parse(args, vars.iter().cloned()) {
+        Err(e) => e,
+        Ok(_) => panic!("Configuration accepted.")
+    }
+}
+
+/// Returns a fresh path in the temp directory.
+fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("yocto-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
+}
+
+fn timestamp() -> u64 {
+    thread::sleep(Duration::from_millis(10));
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let ts = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+    thread::sleep(Duration::from_millis(10));
+    ts
+}
+
+/// Asserts that the connection is closed without a response.
+fn assert_refused(iface: &str) {
+    let mut stream = TcpStream::connect(iface).unwrap();
+    let _ = stream.write_all(b"TEST");
+    let mut response = Vec::new();
+    if stream.read_to_end(&mut response).is_ok() {
+        assert!(response.is_empty());
+    }
+}
+
 fn assert_error(response: String) {
     let split: Vec<&str> = response.split(SEP).collect();
     if split[0] != "ERR" {
@@ -163,3 +1651,11 @@ fn assert_ok(response: String, with_value: Option<String>) {
         assert_eq!(split.len(), 1);
     }
 }
+
+/// Returns the fencing token of a successful `LOCK` or `REFRESH`.
+fn token(response: String) -> u64 {
+    match response.split(SEP).collect::<Vec<&str>>()[..] {
+        ["OK", token] => token.parse().unwrap(),
+        _ => panic!("No fencing token sent: {}", response)
+    }
+}